@@ -0,0 +1,1051 @@
+
+use crate::config::Config;
+use crate::hash_table::HashMap;
+use crate::loader::{ingest_csv, Tables};
+use crate::mmap_index::MappedIndex;
+use crate::output::*;
+use crate::positions;
+use crate::prints::*;
+use crate::query::{parse_find_query, parse_stats_query, players_with_prefix, run_find_query, run_stats_query, Field};
+use crate::similar::similar_players;
+use crate::sort::*;
+use crate::structs::*;
+use crate::trie::Trie;
+
+// Settings a running session can change with `set`, plus whether `quit` was
+// asked for
+pub struct Session {
+    pub format: OutputFormat,
+    pub columns: Option<Vec<String>>,
+    pub quit: bool,
+}
+
+impl Session {
+    pub fn new(config: &Config) -> Self {
+        Session {
+            format: config.format,
+            columns: config.columns.clone(),
+            quit: false,
+        }
+    }
+
+    // Columns picked with --columns or `set columns`, otherwise the view's own
+    fn columns<'a>(&'a self, view: &'a [&'a str]) -> Vec<&'a str> {
+        match &self.columns {
+            Some(columns) => columns.iter().map(|c| c.as_str()).collect(),
+            None => view.to_vec(),
+        }
+    }
+}
+
+// Everything a command handler can read or change
+pub struct Context<'a> {
+    pub tables: &'a mut Tables,
+    pub quarantine: &'a mut Quarantine,
+    pub config: &'a Config,
+    pub session: &'a mut Session,
+    // Set when the session answers from --index instead of loaded tables
+    pub index: Option<&'a MappedIndex>,
+}
+
+// Default columns of each view
+const SEARCH_COLUMNS: &[&str] = &["sofifa_id", "short_name", "long_name", "player_positions", "rating", "count"];
+const USER_COLUMNS: &[&str] = &["sofifa_id", "short_name", "long_name", "global_rating", "rating", "global_count"];
+const TAGS_COLUMNS: &[&str] = USER_COLUMNS;
+// How much of a player `show` lists
+const SHOW_TAGS: usize = 10;
+const SHOW_SIMILAR: usize = 5;
+// Players named when a name matches several
+const SHOW_AMBIGUOUS: usize = 5;
+// What a session on an index can do; the index only holds players and
+// their global rating
+const INDEX_COMMANDS: &[&str] = &["player", "show", "set", "help", "quit"];
+
+pub const TOP_COLUMNS: &[&str] = &[
+    "sofifa_id",
+    "short_name",
+    "long_name",
+    "player_positions",
+    "nationality",
+    "club_name",
+    "league_name",
+    "rating",
+    "count",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    // A non-negative whole number
+    Number,
+    // A single word, quotes removed
+    Word,
+    // Every remaining word
+    Rest,
+}
+
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+const fn arg(name: &'static str, kind: ArgKind) -> Arg {
+    Arg {
+        name,
+        kind,
+        optional: false,
+    }
+}
+
+const fn optional(name: &'static str, kind: ArgKind) -> Arg {
+    Arg {
+        name,
+        kind,
+        optional: true,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArgValue {
+    Number(u32),
+    Word(String),
+    Rest(Vec<String>),
+}
+
+// Arguments of one call, checked against the command's declaration
+#[derive(Debug)]
+pub struct Args {
+    values: Vec<(&'static str, ArgValue)>,
+}
+
+impl Args {
+    fn get(&self, name: &str) -> Option<&ArgValue> {
+        self.values.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    pub fn number(&self, name: &str) -> Option<u32> {
+        match self.get(name) {
+            Some(ArgValue::Number(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn word(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(ArgValue::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    pub fn rest(&self, name: &str) -> &[String] {
+        match self.get(name) {
+            Some(ArgValue::Rest(words)) => words,
+            _ => &[],
+        }
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [Arg],
+    // Shown in place of the generated usage when set
+    pub usage: Option<&'static str>,
+    pub summary: &'static str,
+    pub help: &'static str,
+    pub handler: fn(&mut Context, &Args) -> Result<(), String>,
+}
+
+impl Command {
+    pub fn usage(&self) -> String {
+        if let Some(usage) = self.usage {
+            return usage.to_string();
+        }
+
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            let name = match arg.kind {
+                ArgKind::Rest => format!("{}...", arg.name),
+                _ => arg.name.to_string(),
+            };
+            if arg.optional {
+                usage += &format!(" [{}]", name);
+            } else {
+                usage += &format!(" <{}>", name);
+            }
+        }
+        usage
+    }
+
+    fn parse_args(&self, words: &[&str]) -> Result<Args, String> {
+        let mut values = Vec::new();
+        let mut words = words.iter();
+
+        for arg in self.args {
+            if arg.kind == ArgKind::Rest {
+                let rest: Vec<String> = words.by_ref().map(|w| w.to_string()).collect();
+                if rest.is_empty() && !arg.optional {
+                    return Err(format!("Missing {}. Usage: {}", arg.name, self.usage()));
+                }
+                values.push((arg.name, ArgValue::Rest(rest)));
+                continue;
+            }
+
+            let word = match words.next() {
+                Some(word) => word.to_string(),
+                None if arg.optional => continue,
+                None => return Err(format!("Missing {}. Usage: {}", arg.name, self.usage())),
+            };
+
+            let value = match arg.kind {
+                ArgKind::Number => match word.parse::<u32>() {
+                    Ok(n) => ArgValue::Number(n),
+                    Err(_) => {
+                        return Err(format!(
+                            "Invalid {} '{}', expected a number. Usage: {}",
+                            arg.name,
+                            word,
+                            self.usage()
+                        ))
+                    }
+                },
+                _ => ArgValue::Word(word),
+            };
+            values.push((arg.name, value));
+        }
+
+        if words.next().is_some() {
+            return Err(format!("Too many arguments. Usage: {}", self.usage()));
+        }
+        Ok(Args { values })
+    }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "player",
+        aliases: &[],
+        args: &[arg("name", ArgKind::Rest)],
+        usage: None,
+        summary: "players whose name starts with the given prefix",
+        help: "Searches long names by prefix, best rated first.\nExample: player Lionel",
+        handler: player_command,
+    },
+    Command {
+        name: "user",
+        aliases: &[],
+        args: &[arg("user_id", ArgKind::Number)],
+        usage: None,
+        summary: "the 20 players a user rated highest",
+        help: "Lists a user's ratings, highest first, next to each player's global rating.\nExample: user 4",
+        handler: user_command,
+    },
+    Command {
+        name: "show",
+        aliases: &[],
+        args: &[arg("player", ArgKind::Rest)],
+        usage: Some("show <sofifa_id|name>"),
+        summary: "everything about one player",
+        help: "Prints the player's fields, how their ratings are spread, their most used\n\
+               tags and the players most like them (same position, closest attributes).\n\
+               A name must match a single player. JSON prints one object with a key per\n\
+               section; csv cannot hold the sections and is refused.\nExample: show 158023",
+        handler: show_command,
+    },
+    Command {
+        name: "tags",
+        aliases: &[],
+        args: &[arg("tag", ArgKind::Rest)],
+        usage: None,
+        summary: "players carrying every given tag",
+        help: "Players tagged with all of the tags, best rated first. Quote tags with spaces.\nExample: tags Dribbler 'Brazil'",
+        handler: tags_command,
+    },
+    Command {
+        name: "top",
+        aliases: &[],
+        args: &[
+            arg("count", ArgKind::Number),
+            arg("position", ArgKind::Word),
+            optional("option", ArgKind::Rest),
+        ],
+        usage: Some("top<N> <position> [primary] [page P | offset K]"),
+        summary: "best rated players of a position",
+        help: "Players of a position with at least 1000 ratings, best first. The count\n\
+               goes right after top; page and offset move further down the list. The\n\
+               position is a code (GK, CB, ST, ...) or a group: DEF, MID or ATT.\n\
+               With primary, only the first position each player lists counts.\n\
+               Example: top10 DEF primary page 2",
+        handler: top_command,
+    },
+    Command {
+        name: "club",
+        aliases: &[],
+        args: &[arg("name", ArgKind::Rest)],
+        usage: None,
+        summary: "players of the clubs whose name starts with the prefix",
+        help: "Every player of the matching clubs, best rated first.\nExample: club Manchester",
+        handler: club_command,
+    },
+    Command {
+        name: "league",
+        aliases: &[],
+        args: &[arg("name", ArgKind::Rest)],
+        usage: None,
+        summary: "players of the leagues whose name starts with the prefix",
+        help: "Every player of the matching leagues, best rated first. Use find with\n\
+               limit for a shorter list.\nExample: league Spain",
+        handler: league_command,
+    },
+    Command {
+        name: "country",
+        aliases: &[],
+        args: &[arg("name", ArgKind::Rest)],
+        usage: None,
+        summary: "players of the nationalities that start with the prefix",
+        help: "Every player of the matching nationalities, best rated first. Use find\n\
+               with limit for a shorter list.\nExample: country Brazil",
+        handler: country_command,
+    },
+    Command {
+        name: "find",
+        aliases: &[],
+        args: &[optional("clause", ArgKind::Rest)],
+        usage: Some("find [key=value...] [sort=<column> [asc|desc]]"),
+        summary: "players matching position, nationality, club or league",
+        help: "Filters: position, nationality (or country), club, league, name (part of\n\
+               it), min_count, min_rating. Several values separated by commas match any\n\
+               of them. Sorts by rating, best first, unless sort names a column; limit=N\n\
+               (20 by default) and offset=K pick the page.\n\
+               Example: find position=ST nationality=Brazil league='Spain Primera Division' min_count=500 sort=rating desc limit=20",
+        handler: find_players_command,
+    },
+    Command {
+        name: "stats",
+        aliases: &[],
+        args: &[arg("clause", ArgKind::Rest)],
+        usage: Some("stats by=<field> [key=value...] [top=N]"),
+        summary: "rating statistics per club, league, nationality or position",
+        help: "Groups the players by club, league, nationality or position and shows\n\
+               how many there are, how many were rated, their ratings, the mean of\n\
+               their averages and the best rated, best groups first. Takes the same\n\
+               filters as find; top=N (10 by default) keeps the first groups.\n\
+               Example: stats by=club position=GK min_count=100 top=10",
+        handler: stats_command,
+    },
+    Command {
+        name: "ingest",
+        aliases: &[],
+        args: &[arg("path", ArgKind::Rest)],
+        usage: None,
+        summary: "add the rows of another ratings or tags file",
+        help: "Loads an extra ratings or tags file into the running tables; its kind is\n\
+               told apart by a rating or tag column.\nExample: ingest new_ratings.csv",
+        handler: ingest_command,
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        args: &[arg("setting", ArgKind::Word), arg("value", ArgKind::Rest)],
+        usage: None,
+        summary: "change the output format or columns",
+        help: "Output settings for the rest of the session:\n\
+               set format table|json|csv|markdown\n\
+               set columns <a,b,...>|default\n\
+               Example: set columns sofifa_id,short_name,rating",
+        handler: set_command,
+    },
+    Command {
+        name: "help",
+        aliases: &[],
+        args: &[optional("command", ArgKind::Word)],
+        usage: None,
+        summary: "list the commands, or explain one",
+        help: "Example: help top",
+        handler: help_command,
+    },
+    Command {
+        name: "quit",
+        aliases: &["exit", "n"],
+        args: &[],
+        usage: None,
+        summary: "leave",
+        help: "Also exit, or n.",
+        handler: quit_command,
+    },
+];
+
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    let name = name.to_lowercase();
+    COMMANDS
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name.as_str()))
+}
+
+// Runs one command line. Anything that keeps the command from producing its
+// result (bad arguments, unknown ids) comes back as an error.
+pub fn run_command(line: &str, ctx: &mut Context) -> Result<(), String> {
+    let tokens = tokenize(line)?;
+    let mut words: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+
+    if words.is_empty() {
+        return Ok(());
+    }
+
+    // "top10" is "top" with its count attached
+    let count;
+    if let Some(suffix) = words[0].to_lowercase().strip_prefix("top") {
+        if !suffix.is_empty() {
+            count = suffix.to_string();
+            words[0] = "top";
+            words.insert(1, &count);
+        }
+    }
+
+    let command = match find_command(words[0]) {
+        Some(command) => command,
+        None => return Err(format!("Unknown command '{}', type help for the list", words[0])),
+    };
+    if ctx.index.is_some() && !INDEX_COMMANDS.contains(&command.name) {
+        return Err(format!("'{}' needs the full tables, it is not available with --index", command.name));
+    }
+    let args = command.parse_args(&words[1..])?;
+    (command.handler)(ctx, &args)
+}
+
+fn player_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let name = args.rest("name").join(" ");
+    let columns = ctx.session.columns(SEARCH_COLUMNS);
+    if let Some(index) = ctx.index {
+        let id = indexed_player(index, &name)?;
+        return print_indexed_players(index, &[id], &columns, ctx.session.format);
+    }
+    get_player_start_with(
+        &name,
+        &ctx.tables.name_index,
+        &ctx.tables.players_table,
+        &ctx.tables.rating_table,
+        &columns,
+        ctx.session.format,
+    )
+}
+
+fn user_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let id = args.number("user_id").unwrap_or_default();
+    let tables = &mut *ctx.tables;
+
+    let user = match tables.user_table.search(&id) {
+        Some(user) => user,
+        None => return Err("User does not exist".to_string()),
+    };
+
+    let user_rating: &mut Vec<RatingPlayer> = &mut user.ratings;
+    sort_user_ratings(user_rating);
+    sort_user_global_ratings(user_rating, &tables.rating_table);
+
+    let rows: Vec<PlayerRow> = user_rating
+        .iter()
+        .take(20)
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    print_player_rows(&rows, &ctx.session.columns(USER_COLUMNS), ctx.session.format)
+}
+
+fn tags_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let tags = args.rest("tag");
+    let tables = &mut *ctx.tables;
+
+    let mut players: Vec<_> = tables.tag_player.get_id(&tags[0]).unwrap_or_default();
+
+    for tag in tags.iter().skip(1) {
+        if let Some(search) = tables.tag_player.get_id(tag) {
+            players.retain(|id| search.contains(id));
+        }
+    }
+
+    let mut user_rating: Vec<RatingPlayer> = Vec::new();
+
+    for player in players {
+        if let Some(n) = tables.rating_table.search_non_mut(&player) {
+            user_rating.push(n.clone());
+        }
+    }
+
+    sort_user_ratings(&mut user_rating);
+    let rows: Vec<PlayerRow> = user_rating
+        .iter()
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    print_player_rows(&rows, &ctx.session.columns(TAGS_COLUMNS), ctx.session.format)
+}
+
+fn show_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let query = args.rest("player").join(" ");
+    let format = ctx.session.format;
+    let mut sections = Sections::new();
+    if let Some(index) = ctx.index {
+        let id = indexed_player(index, &query)?;
+        let (player, rating) = match (index.player(id)?, index.rating(id)) {
+            (Some(player), Some(rating)) => (player, rating),
+            _ => return Err(format!("No player {} in the index", id)),
+        };
+        let row = PlayerRow {
+            rating: &rating,
+            player: Some(PlayerSource::Index(player)),
+            global: Some(&rating),
+        };
+        sections.push("profile", "", profile_rows(&row));
+        sections.print(format)?;
+        note(format, "Ratings spread, tags and similar players are not in the index");
+        return Ok(());
+    }
+    let tables = &*ctx.tables;
+
+    let id = find_player(tables, &query)?;
+    let rating = match tables.rating_table.search_non_mut(&id) {
+        Some(rating) => rating,
+        None => return Err(format!("Player {} has no ratings table entry", id)),
+    };
+    let row = PlayerRow::new(rating, &tables.players_table, &tables.rating_table);
+    sections.push("profile", "", profile_rows(&row));
+
+    if let Some(histogram) = tables.histograms.search_non_mut(&id) {
+        let title = format!("Ratings ({} users)", histogram.buckets.iter().sum::<u32>());
+        sections.push("histogram", title, histogram_rows(histogram, format));
+    }
+
+    let tags = match tables.player_tags.search_non_mut(&id) {
+        Some(tags) => tags.top(SHOW_TAGS),
+        None => Vec::new(),
+    };
+    let title = if tags.is_empty() { "No tags" } else { "Top tags" };
+    sections.push("tags", title, tag_rows(&tags));
+
+    let similar = similar_players(tables, id, SHOW_SIMILAR);
+    let rows: Vec<PlayerRow> = similar
+        .iter()
+        .filter_map(|id| tables.rating_table.search_non_mut(id))
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    let title = if rows.is_empty() { "No similar players" } else { "Similar players" };
+    sections.push("similar", title, player_rows(&rows, &ctx.session.columns(SEARCH_COLUMNS))?);
+    sections.print(format)
+}
+
+// An index is only searched by sofifa_id
+fn indexed_player(index: &MappedIndex, query: &str) -> Result<u32, String> {
+    let id = match query.trim().parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => return Err(format!("'{}' is not a sofifa_id, the index is searched by id only", query)),
+    };
+    match index.player(id)? {
+        Some(_) => Ok(id),
+        None => Err(format!("No player {} in the index", id)),
+    }
+}
+
+// A sofifa_id, or a long name (or its start) that matches a single player
+fn find_player(tables: &Tables, query: &str) -> Result<u32, String> {
+    if let Ok(id) = query.parse::<u32>() {
+        if tables.players_table.search_non_mut(&id).is_some() {
+            return Ok(id);
+        }
+    }
+
+    // A full name wins over longer names it is the start of
+    let mut ids = tables.name_index.get_id(query).unwrap_or_default();
+    if ids.is_empty() {
+        for name in tables.name_index.get_words_starting_with(query) {
+            ids.extend(tables.name_index.get_id(&name).unwrap_or_default());
+        }
+    }
+
+    match ids.len() {
+        0 => Err(format!("No player matches '{}'", query)),
+        1 => Ok(ids[0]),
+        n => {
+            let names: Vec<String> = ids
+                .iter()
+                .take(SHOW_AMBIGUOUS)
+                .filter_map(|id| tables.players_table.search_non_mut(id))
+                .map(|player| format!("{} ({})", player.long_name, player.sofifa_id))
+                .collect();
+            let more = if n > SHOW_AMBIGUOUS { ", ..." } else { "" };
+            Err(format!(
+                "{} players match '{}', use the sofifa_id or more of the name: {}{}",
+                n,
+                query,
+                names.join(", "),
+                more
+            ))
+        }
+    }
+}
+
+fn club_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    indexed_players_command(ctx, args, Field::Club, "club")
+}
+
+fn league_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    indexed_players_command(ctx, args, Field::League, "league")
+}
+
+fn country_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    indexed_players_command(ctx, args, Field::Nationality, "nationality")
+}
+
+// Players whose club, league or nationality starts with the prefix
+fn indexed_players_command(ctx: &mut Context, args: &Args, field: Field, what: &str) -> Result<(), String> {
+    let prefix = args.rest("name").join(" ");
+    let format = ctx.session.format;
+    let tables = &*ctx.tables;
+
+    let (values, ids) = players_with_prefix(tables, field, &prefix);
+    if values.is_empty() {
+        return Err(format!("No {} starts with '{}'", what, prefix));
+    }
+    if values.len() > 1 {
+        note(format, &format!("{} {} values match: {}", values.len(), what, values.join(", ")));
+    }
+
+    let mut ratings: Vec<&RatingPlayer> = ids
+        .iter()
+        .filter_map(|id| tables.rating_table.search_non_mut(id))
+        .collect();
+    sort_players_by_rating(&mut ratings);
+
+    let rows: Vec<PlayerRow> = ratings
+        .iter()
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    print_player_rows(&rows, &ctx.session.columns(TOP_COLUMNS), format)
+}
+
+fn find_players_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let query = parse_find_query(args.rest("clause"))?;
+    let format = ctx.session.format;
+    let tables = &*ctx.tables;
+
+    let ratings = run_find_query(tables, &query);
+    if query.offset >= ratings.len() {
+        note(format, &format!("Nothing to show: {} players match", ratings.len()));
+        return Ok(());
+    }
+    let end = page_end(ratings.len(), query.offset, query.limit);
+    note(format, &format!("{} players match, showing {}", ratings.len(), end - query.offset));
+
+    let rows: Vec<PlayerRow> = ratings[query.offset..end]
+        .iter()
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    print_player_rows(&rows, &ctx.session.columns(TOP_COLUMNS), format)
+}
+
+fn stats_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let query = parse_stats_query(args.rest("clause"))?;
+    let format = ctx.session.format;
+
+    let (groups, total) = run_stats_query(ctx.tables, &query);
+    if groups.is_empty() {
+        note(format, "No players match");
+        return Ok(());
+    }
+    note(format, &format!("{} groups, showing {}", total, groups.len()));
+    print_group_stats(&groups, query.by.name(), format);
+    Ok(())
+}
+
+fn ingest_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let path = args.rest("path").join(" ");
+    let format = ctx.session.format;
+
+    match ingest_csv(&path, ctx.tables, ctx.config.dedup, &ctx.config.column_map, ctx.quarantine) {
+        Ok(summary) => {
+            print_load_report(&[summary.report], format, ctx.config.batch());
+            note(
+                format,
+                &format!(
+                    "Ingested {} {} ({} for unknown players), {} players' averages changed",
+                    summary.rows, summary.kind, summary.orphans, summary.players_changed
+                ),
+            );
+            Ok(())
+        }
+        Err(e) => Err(format!("Could not ingest {}: {}", path, e)),
+    }
+}
+
+fn top_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let query = parse_top_query(
+        args.number("count").unwrap_or_default() as usize,
+        args.word("position").unwrap_or_default(),
+        args.rest("option"),
+    )?;
+    let format = ctx.session.format;
+    let tables = &mut *ctx.tables;
+
+    let mut players_in_position: Vec<u32> = query
+        .codes
+        .iter()
+        .flat_map(|code| tables.position_player.get_id(code).unwrap_or_default())
+        .collect();
+    players_in_position.sort_unstable();
+    players_in_position.dedup();
+    if query.primary {
+        players_in_position.retain(|id| {
+            let player = tables.players_table.search_non_mut(id);
+            let first = player.and_then(|p| p.positions().first().copied());
+            first.is_some_and(|code| query.codes.contains(&code))
+        });
+    }
+    if players_in_position.is_empty() {
+        let primary = if query.primary { "primary " } else { "" };
+        return Err(format!("No players in {}position {}", primary, query.position));
+    }
+
+    let mut players_position_ratings = Vec::new();
+    for player in players_in_position {
+        if let Some(rating) = tables.rating_table.search_non_mut(&player) {
+            if rating.num_ratings >= 1000 {
+                players_position_ratings.push(rating);
+            }
+        }
+    }
+    sort_players_by_rating(&mut players_position_ratings);
+
+    let available = players_position_ratings.len();
+    if query.offset >= available {
+        note(
+            format,
+            &format!(
+                "Nothing to show: only {} players with enough ratings in position {}",
+                available, query.position
+            ),
+        );
+        return Ok(());
+    }
+
+    let end = page_end(available, query.offset, query.count);
+    if end - query.offset < query.count {
+        note(
+            format,
+            &format!(
+                "Only {} players with enough ratings in position {}, showing {}",
+                available,
+                query.position,
+                end - query.offset
+            ),
+        );
+    }
+
+    let rows: Vec<PlayerRow> = players_position_ratings[query.offset..end]
+        .iter()
+        .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+        .collect();
+    print_player_rows(&rows, &ctx.session.columns(TOP_COLUMNS), format)
+}
+
+fn set_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let value = args.rest("value").join(" ");
+    let session = &mut *ctx.session;
+
+    match (args.word("setting").unwrap_or_default(), value.as_str()) {
+        ("format", value) => session.format = value.parse::<OutputFormat>()?,
+        ("columns", "default") => session.columns = None,
+        ("columns", value) => {
+            let columns = parse_columns(value)?;
+            select_columns(&player_columns(), &columns)?;
+            session.columns = Some(columns);
+        }
+        (setting, _) => {
+            return Err(format!(
+                "Unknown setting '{}', expected format or columns",
+                setting
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn help_command(ctx: &mut Context, args: &Args) -> Result<(), String> {
+    let format = ctx.session.format;
+    match args.word("command") {
+        Some(name) => {
+            let name = name.strip_prefix("top").filter(|n| n.parse::<u32>().is_ok()).map_or(name, |_| "top");
+            let command = match find_command(name) {
+                Some(command) => command,
+                None => return Err(format!("Unknown command '{}', type help for the list", name)),
+            };
+            note(format, &command.usage());
+            if !command.aliases.is_empty() {
+                note(format, &format!("aliases: {}", command.aliases.join(", ")));
+            }
+            note(format, command.summary);
+            note(format, command.help);
+        }
+        None => {
+            let usages: Vec<String> = COMMANDS.iter().map(|c| c.usage()).collect();
+            let width = usages.iter().map(|u| u.len()).max().unwrap_or(0);
+            for (command, usage) in COMMANDS.iter().zip(&usages) {
+                note(format, &format!("  {:width$}  {}", usage, command.summary, width = width));
+            }
+            note(format, "Type help <command> for details.");
+        }
+    }
+    Ok(())
+}
+
+fn quit_command(ctx: &mut Context, _args: &Args) -> Result<(), String> {
+    ctx.session.quit = true;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct TopQuery {
+    count: usize,
+    // The position or group as typed, and the codes it stands for
+    position: String,
+    codes: Vec<&'static str>,
+    // Only players whose first listed position is one of the codes
+    primary: bool,
+    offset: usize,
+}
+
+// Reads the options of "topN <position> [primary] [page P | offset K]"
+fn parse_top_query(count: usize, position: &str, options: &[String]) -> Result<TopQuery, String> {
+    if count == 0 {
+        return Err("Invalid top number '0'".to_string());
+    }
+    let codes = positions::expand(position)?;
+
+    let mut primary = false;
+    let mut offset = None;
+    let mut options = options.iter();
+    while let Some(keyword) = options.next() {
+        let keyword = keyword.to_lowercase();
+        if keyword == "primary" {
+            primary = true;
+            continue;
+        }
+        if keyword != "page" && keyword != "offset" {
+            return Err(format!("Unknown option '{}', expected primary, page or offset", keyword));
+        }
+        if offset.is_some() {
+            return Err("Give either page or offset, once".to_string());
+        }
+
+        let value = match options.next().map(|value| (value, value.parse::<usize>())) {
+            Some((_, Ok(value))) => value,
+            Some((value, Err(_))) => return Err(format!("Invalid {} number '{}'", keyword, value)),
+            None => return Err(format!("Missing {} number", keyword)),
+        };
+        offset = Some(match keyword.as_str() {
+            "page" if value > 0 => match (value - 1).checked_mul(count) {
+                Some(offset) => offset,
+                None => return Err("page too large".to_string()),
+            },
+            "page" => return Err("Pages start at 1".to_string()),
+            _ => value,
+        });
+    }
+
+    Ok(TopQuery {
+        count,
+        position: position.to_uppercase(),
+        codes,
+        primary,
+        offset: offset.unwrap_or(0),
+    })
+}
+
+// End of the page that starts at `offset`, clamped to what is available
+fn page_end(available: usize, offset: usize, count: usize) -> usize {
+    std::cmp::min(offset.saturating_add(count), available)
+}
+
+// Splits a command line into words the way a shell would: single quotes keep
+// everything literally, double quotes allow \" and \\, a backslash outside
+// quotes escapes the next character. Quoted parts glue onto their neighbours,
+// so "N'Golo Kanté", N\'Golo and 'N'"'"'Golo' are all the same name.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // A word can be empty ('') but still present
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated ' quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("Unterminated \" quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated \" quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => {
+                    in_word = true;
+                    word.push(c);
+                }
+                None => return Err("Nothing to escape after \\ at the end of the line".to_string()),
+            },
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// The word written so tokenize reads it back: left alone when nothing in it
+// is special, single-quoted when it has no ' and double-quoted otherwise
+pub fn quote(word: &str) -> String {
+    if !word.is_empty() && !word.contains(|c: char| c.is_whitespace() || matches!(c, '\'' | '"' | '\\')) {
+        return word.to_string();
+    }
+    if !word.contains('\'') {
+        return format!("'{}'", word);
+    }
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn get_player_start_with(
+    prefix: &str,
+    trie: &Trie,
+    players_table: &HashMap<u32, Player>,
+    rating_table: &HashMap<u32, RatingPlayer>,
+    columns: &[&str],
+    format: OutputFormat,
+) -> Result<(), String> {
+    let mut ratings = Vec::<RatingPlayer>::new();
+
+    for name in trie.get_words_starting_with(prefix) {
+        match trie.get_id(&name) {
+            Some(n) => {
+                for i in n {
+                    if let Some(rate) = rating_table.search_non_mut(&i) {
+                        ratings.push(rate.clone());
+                    }
+                }
+            }
+            None => {
+                note(format, &format!("No match found for {}", name));
+            }
+        }
+    }
+    sort_user_ratings(&mut ratings);
+
+    let rows: Vec<PlayerRow> = ratings
+        .iter()
+        .map(|rating| PlayerRow::new(rating, players_table, rating_table))
+        .collect();
+    print_player_rows(&rows, columns, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let top = find_command("top").unwrap();
+        let args = top.parse_args(&["10", "ST", "page", "2"]).unwrap();
+        assert_eq!(args.number("count"), Some(10));
+        assert_eq!(args.word("position"), Some("ST"));
+        assert_eq!(args.rest("option"), &["page".to_string(), "2".to_string()]);
+
+        let user = find_command("user").unwrap();
+        assert!(user.parse_args(&["abc"]).unwrap_err().starts_with("Invalid user_id 'abc'"));
+        assert!(user.parse_args(&[]).unwrap_err().starts_with("Missing user_id"));
+        assert!(user.parse_args(&["1", "2"]).unwrap_err().starts_with("Too many arguments"));
+
+        assert_eq!(find_command("EXIT").map(|c| c.name), Some("quit"));
+        assert_eq!(find_command("help").unwrap().usage(), "help [command]");
+        assert!(find_command("bogus").is_none());
+    }
+
+    #[test]
+    fn test_parse_top_query() {
+        let options = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+        let query = parse_top_query(10, "def", &options(&["primary", "page", "2"])).unwrap();
+        assert_eq!(query.position, "DEF");
+        assert_eq!(query.codes, vec!["CB", "LB", "RB", "LWB", "RWB"]);
+        assert!(query.primary);
+        assert_eq!(query.offset, 10);
+
+        let query = parse_top_query(5, "ST", &options(&["offset", "3"])).unwrap();
+        assert_eq!((query.codes, query.primary, query.offset), (vec!["ST"], false, 3));
+
+        assert!(parse_top_query(5, "XX", &[]).unwrap_err().starts_with("Unknown position 'XX'"));
+        assert!(parse_top_query(5, "ST", &options(&["page", "0"])).is_err());
+        assert!(parse_top_query(5, "ST", &options(&["page"])).is_err());
+        assert!(parse_top_query(5, "ST", &options(&["page", "1", "offset", "2"])).is_err());
+        assert!(parse_top_query(5, "ST", &options(&["sideways"])).is_err());
+        assert_eq!(
+            parse_top_query(usize::MAX, "ST", &options(&["page", "99999999999"])).unwrap_err(),
+            "page too large"
+        );
+    }
+
+    #[test]
+    fn test_page_end() {
+        // Fewer players than asked for: the page is cut short
+        assert_eq!(page_end(3, 0, 10), 3);
+        assert_eq!(page_end(25, 10, 10), 20);
+        assert_eq!(page_end(25, 20, 10), 25);
+        assert_eq!(page_end(25, usize::MAX, usize::MAX), 25);
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let words = |line: &str| tokenize(line).unwrap();
+
+        assert_eq!(words("  top10   ST page 2 "), ["top10", "ST", "page", "2"]);
+        assert_eq!(words("tags 'Distance Shooter' Dribbler"), ["tags", "Distance Shooter", "Dribbler"]);
+        assert_eq!(words("player \"N'Golo Kanté\""), ["player", "N'Golo Kanté"]);
+        assert_eq!(words("player N\\'Golo"), ["player", "N'Golo"]);
+        assert_eq!(words("'N'\"'\"'Golo'"), ["N'Golo"]);
+        assert_eq!(words(r#""say \"hi\" \\ \n""#), [r#"say "hi" \ \n"#]);
+        assert_eq!(words("tags '' x"), ["tags", "", "x"]);
+        assert_eq!(words("a\\ b"), ["a b"]);
+        assert!(words("   ").is_empty());
+
+        assert_eq!(tokenize("tags 'Distance").unwrap_err(), "Unterminated ' quote");
+        assert_eq!(tokenize("player \"Kanté").unwrap_err(), "Unterminated \" quote");
+        assert_eq!(tokenize("player \"Kanté\\\"").unwrap_err(), "Unterminated \" quote");
+        assert!(tokenize("player Kanté\\").is_err());
+    }
+}
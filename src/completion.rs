@@ -0,0 +1,335 @@
+use rustyline::completion::{Completer, Pair};
+use rustyline::{Helper, Highlighter, Hinter, Validator};
+use std::env;
+use std::path::PathBuf;
+
+use crate::commands::{quote, tokenize, COMMANDS};
+use crate::loader::Tables;
+use crate::positions::GROUPS as GROUPS_OF_POSITIONS;
+use crate::prints::player_columns;
+use crate::query::{clauses, Field, FIND_CLAUSES, STATS_CLAUSES};
+use crate::trie::Trie;
+
+// More candidates than this are not worth listing
+const MAX_CANDIDATES: usize = 100;
+
+const SETTINGS: &[&str] = &["format", "columns"];
+const FORMATS: &[&str] = &["table", "json", "csv", "markdown"];
+const GROUPS: &[&str] = &["club", "league", "nationality", "position"];
+const TOP_OPTIONS: &[&str] = &["primary", "page", "offset"];
+
+// Tab completion for the prompt. The editor keeps its helper for the whole
+// session, so it works on copies of the tries; the tags one is refreshed
+// after an ingest.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+pub struct ReplHelper {
+    names: Trie,
+    tags: Trie,
+    positions: Trie,
+    nationalities: Trie,
+    clubs: Trie,
+    leagues: Trie,
+}
+
+impl ReplHelper {
+    pub fn new(tables: &Tables) -> Self {
+        ReplHelper {
+            names: tables.name_index.clone(),
+            tags: tables.tag_player.clone(),
+            positions: tables.position_player.clone(),
+            nationalities: tables.nationality_player.clone(),
+            clubs: tables.club_player.clone(),
+            leagues: tables.league_player.clone(),
+        }
+    }
+
+    pub fn refresh_tags(&mut self, tables: &Tables) {
+        self.tags = tables.tag_player.clone();
+    }
+
+    // Start of the text to replace and its candidates, given what is typed
+    // before the cursor
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let word_start = line
+            .rfind(|c: char| c.is_whitespace() || c == '\'' || c == '"')
+            .map_or(0, |i| i + 1);
+        let word = &line[word_start..];
+
+        // Still on the command name
+        if !line[..word_start].contains(|c: char| !c.is_whitespace()) {
+            let names = COMMANDS.iter().flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()));
+            return (word_start, starting_with(names, word));
+        }
+
+        let command = line.split_whitespace().next().unwrap_or_default().to_lowercase();
+        // Words before the one being completed
+        let done = line[..word_start].split_whitespace().count();
+
+        match command.as_str() {
+            // Names have spaces, so the whole rest of the line is the prefix.
+            // The command joins its words again, so only quotes and
+            // backslashes need quoting.
+            "player" | "show" | "club" | "league" | "country" => {
+                let start = line.len() - line.trim_start()[command.len()..].trim_start().len();
+                let index = match command.as_str() {
+                    "club" => &self.clubs,
+                    "league" => &self.leagues,
+                    "country" => &self.nationalities,
+                    _ => &self.names,
+                };
+                // A lone ' is more likely part of the name than a quote
+                let rest = &line[start..];
+                let mut names = index.get_words_starting_with(&typed_rest(rest));
+                names.extend(index.get_words_starting_with(rest));
+                names.sort();
+                names.dedup();
+                let names = names.into_iter().map(|name| {
+                    if name.contains(['\'', '"', '\\']) {
+                        quote(&name)
+                    } else {
+                        name
+                    }
+                });
+                (start, names.collect())
+            }
+            "tags" => {
+                let open = line[..word_start].chars().last().filter(|c| matches!(c, '\'' | '"'));
+                let tags = self.tags.get_words_starting_with(word).into_iter();
+                (word_start, tags.map(|tag| quote_within(&tag, open)).collect())
+            }
+            "help" if done == 1 => {
+                let names = COMMANDS.iter().map(|c| c.name);
+                (word_start, starting_with(names, word))
+            }
+            "set" if done == 1 => (word_start, starting_with(SETTINGS.iter().copied(), word)),
+            "set" if done == 2 => match line.split_whitespace().nth(1) {
+                Some("format") => (word_start, starting_with(FORMATS.iter().copied(), word)),
+                // Columns are a comma-separated list, complete the last one
+                Some("columns") => {
+                    let start = word_start + word.rfind(',').map_or(0, |i| i + 1);
+                    let names = player_columns().iter().map(|c| c.name).collect::<Vec<_>>();
+                    (start, starting_with(names.into_iter(), &line[start..]))
+                }
+                _ => (word_start, Vec::new()),
+            },
+            "find" => self.clause_candidates(line, FIND_CLAUSES),
+            "stats" => self.clause_candidates(line, STATS_CLAUSES),
+            top if top.starts_with("top") && done == 1 => (word_start, self.position_candidates(word)),
+            top if top.starts_with("top") => (word_start, starting_with(TOP_OPTIONS.iter().copied(), word)),
+            _ => (word_start, Vec::new()),
+        }
+    }
+
+    // Positions seen in the players file, and the groups
+    fn position_candidates(&self, prefix: &str) -> Vec<String> {
+        let groups = GROUPS_OF_POSITIONS.iter().map(|(group, _)| group.to_lowercase()).collect::<Vec<_>>();
+        let groups = starting_with(groups.iter().map(|group| group.as_str()), prefix);
+        let positions = self.positions.get_words_starting_with(prefix);
+        positions.into_iter().chain(groups).map(|p| p.to_uppercase()).collect()
+    }
+
+    // find and stats clauses: the key, then values of the indexed fields
+    // (after the last comma), sort columns and groups
+    fn clause_candidates(&self, line: &str, own: &[&'static str]) -> (usize, Vec<String>) {
+        let start = clause_start(line);
+        let clause = &line[start..];
+        let (key, value) = match clause.split_once('=') {
+            Some(split) => split,
+            None => {
+                let keys = clauses(own).iter().map(|key| format!("{}=", key)).collect::<Vec<_>>();
+                let mut words = keys.iter().map(|key| key.as_str()).collect::<Vec<_>>();
+                if own.contains(&"sort") {
+                    words.extend(["asc", "desc"]);
+                }
+                return (start, starting_with(words.into_iter(), clause));
+            }
+        };
+
+        let open = value.chars().next().filter(|c| matches!(c, '\'' | '"'));
+        let value_start = start + key.len() + 1 + open.is_some() as usize;
+        let value_start = value_start + line[value_start..].rfind(',').map_or(0, |i| i + 1);
+        let prefix = &line[value_start..];
+
+        let key = key.to_lowercase();
+        if key == "sort" {
+            let names = player_columns().iter().map(|c| c.name).collect::<Vec<_>>();
+            return (value_start, starting_with(names.into_iter(), prefix));
+        }
+        if key == "by" {
+            return (value_start, starting_with(GROUPS.iter().copied(), prefix));
+        }
+        let index = match Field::parse(&key) {
+            Some(Field::Position) => return (value_start, self.position_candidates(prefix)),
+            Some(Field::Nationality) => &self.nationalities,
+            Some(Field::Club) => &self.clubs,
+            Some(Field::League) => &self.leagues,
+            None => return (value_start, Vec::new()),
+        };
+        let values = index.get_words_starting_with(prefix).into_iter();
+        (value_start, values.map(|value| quote_within(&value, open)).collect())
+    }
+}
+
+// What a command reads from the rest of a line still being typed: its words
+// joined by single spaces, with a quote left open closed first
+fn typed_rest(text: &str) -> String {
+    if let Ok(words) = tokenize(text) {
+        let mut rest = words.join(" ");
+        if !rest.is_empty() && text.ends_with(char::is_whitespace) {
+            rest.push(' ');
+        }
+        return rest;
+    }
+    ["'", "\""]
+        .iter()
+        .find_map(|close| tokenize(&format!("{}{}", text, close)).ok())
+        .map_or_else(|| text.to_string(), |words| words.join(" "))
+}
+
+// A candidate written so tokenize reads it back, inside the quote already
+// opened if any. Single quotes cannot hold a ', so it closes them, is
+// escaped and opens them again.
+fn quote_within(word: &str, open: Option<char>) -> String {
+    match open {
+        Some('\'') => word.replace('\'', "'\\''"),
+        Some(_) => word.replace('\\', "\\\\").replace('"', "\\\""),
+        None => quote(word),
+    }
+}
+
+// Where the clause under the cursor starts: after the last space that is not
+// inside quotes
+fn clause_start(line: &str) -> usize {
+    let mut start = 0;
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, c) if c.is_whitespace() => start = i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    start
+}
+
+fn starting_with<'a, I: Iterator<Item = &'a str>>(words: I, prefix: &str) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    words
+        .filter(|word| word.starts_with(&prefix))
+        .map(|word| word.to_string())
+        .collect()
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, mut candidates) = self.candidates(&line[..pos]);
+        candidates.sort();
+        candidates.dedup();
+        candidates.truncate(MAX_CANDIDATES);
+
+        let pairs = candidates
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+// History lives in TF_CPD_HISTORY, or ~/.tf_cpd_history
+pub fn history_path() -> Option<PathBuf> {
+    if let Ok(path) = env::var("TF_CPD_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".tf_cpd_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut tables = Tables::new();
+        tables.name_index.insert_with_id("Lionel Messi", 1);
+        tables.name_index.insert_with_id("Lionel Scaloni", 2);
+        tables.tag_player.insert_with_id("Dribbler", 1);
+        tables.tag_player.insert_with_id("Distance Shooter", 1);
+        tables.position_player.insert_with_id("ST", 1);
+        tables.league_player.insert_with_id("Spain Primera Division", 1);
+        let helper = ReplHelper::new(&tables);
+
+        assert_eq!(helper.candidates("us"), (0, vec!["user".to_string()]));
+        let (start, mut names) = helper.candidates("player lionel ");
+        names.sort();
+        assert_eq!(start, 7);
+        assert_eq!(names, vec!["lionel messi", "lionel scaloni"]);
+        assert_eq!(
+            helper.candidates("tags Dribbler di"),
+            (14, vec!["'distance shooter'".to_string()])
+        );
+        assert_eq!(helper.candidates("tags 'dis"), (6, vec!["distance shooter".to_string()]));
+        assert_eq!(helper.candidates("tags \"dis"), (6, vec!["distance shooter".to_string()]));
+        assert_eq!(helper.candidates("top10 s"), (6, vec!["ST".to_string()]));
+        assert_eq!(helper.candidates("top10 d"), (6, vec!["DEF".to_string()]));
+        assert_eq!(helper.candidates("top10 DEF pr"), (10, vec!["primary".to_string()]));
+        assert_eq!(helper.candidates("set format j"), (11, vec!["json".to_string()]));
+        assert_eq!(
+            helper.candidates("set columns sofifa_id,short"),
+            (22, vec!["short_name".to_string()])
+        );
+        assert_eq!(helper.candidates("find pos"), (5, vec!["position=".to_string()]));
+        assert_eq!(helper.candidates("find position=LW,s"), (17, vec!["ST".to_string()]));
+        assert_eq!(
+            helper.candidates("find league=sp"),
+            (12, vec!["'spain primera division'".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("find league='spain pr"),
+            (13, vec!["spain primera division".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("league spain "),
+            (7, vec!["spain primera division".to_string()])
+        );
+        assert_eq!(helper.candidates("stats by=l"), (9, vec!["league".to_string()]));
+        assert_eq!(helper.candidates("stats to"), (6, vec!["top=".to_string()]));
+        assert_eq!(helper.candidates("find sort=ove"), (10, vec!["overall".to_string()]));
+    }
+
+    #[test]
+    fn test_candidates_quote_what_tokenize_reads() {
+        let mut tables = Tables::new();
+        tables.name_index.insert_with_id("N'Golo Kanté", 1);
+        tables.club_player.insert_with_id("Newell's Old Boys", 1);
+        let helper = ReplHelper::new(&tables);
+
+        // Complete, close the quote still open and read the line back the
+        // way the command will
+        let completed = |line: &str, close: &str| {
+            let (start, candidates) = helper.candidates(line);
+            assert_eq!(candidates.len(), 1, "{}", line);
+            let line = format!("{}{}{}", &line[..start], candidates[0], close);
+            tokenize(&line).expect(&line)
+        };
+
+        assert_eq!(completed("player n'go", ""), vec!["player", "n'golo kanté"]);
+        assert_eq!(completed("show \"n'golo k", ""), vec!["show", "n'golo kanté"]);
+        assert_eq!(completed("player n\\'golo ", ""), vec!["player", "n'golo kanté"]);
+        assert_eq!(completed("find club=newell", ""), vec!["find", "club=newell's old boys"]);
+        assert_eq!(completed("find club='newell", "'"), vec!["find", "club=newell's old boys"]);
+        assert_eq!(completed("find club=\"newell", "\""), vec!["find", "club=newell's old boys"]);
+    }
+}
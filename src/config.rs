@@ -0,0 +1,330 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::input::{ColumnMap, InputFormat, ReadOptions};
+use crate::output::OutputFormat;
+use crate::prints::{parse_columns, select_columns, player_columns};
+use crate::structs::DedupPolicy;
+
+pub const USAGE: &str = "Usage: tf_cpd [options]
+
+Options:
+  --data-dir <dir>        folder with players.csv, rating.csv and tags.csv (env TF_CPD_DATA_DIR)
+  --players <file>        players file (env TF_CPD_PLAYERS)
+  --ratings <file>        ratings file (env TF_CPD_RATINGS)
+  --tags <file>           tags file (env TF_CPD_TAGS)
+  --input-format <fmt>    csv, tsv or jsonl, otherwise taken from each file extension; .gz files are decompressed
+  --delimiter <char>      field separator for csv input
+  --column-map <file>     JSON file mapping source column names to the expected ones (env TF_CPD_COLUMN_MAP)
+  --threads <n>           loader threads, 1 for single-threaded (env TF_CPD_THREADS)
+  --dedup <policy>        repeated ratings by one user: first, last, average or all (env TF_CPD_DEDUP)
+  --strict                stop and exit with an error on the first bad row
+  --quarantine            keep ratings and tags of unknown players instead of dropping them
+  --save-snapshot <file>  write the built tables to a binary snapshot
+  --load-snapshot <file>  start from a snapshot, falling back to the CSV files when it is stale
+  --save-index <file>     write players and rating averages to a memory-mappable index
+  --index <file>          with --lookup, --query or --script, answer from an index file without
+                          loading the CSVs; only player and show work, given a sofifa id
+  --lookup <ids>          comma-separated sofifa ids to look up in --index
+  --format <fmt>          output format: table, json, csv or markdown (env TF_CPD_FORMAT)
+  --columns <list>        comma-separated columns for player results, e.g. sofifa_id,short_name,rating
+  --query <command>       run one command, print its result and exit (1 if it failed)
+  --script <file>         run the commands in a file, one per line, - for stdin; exits 1 if any failed
+  --help                  show this message";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub players: PathBuf,
+    pub ratings: PathBuf,
+    pub tags: PathBuf,
+    pub input_format: Option<InputFormat>,
+    pub delimiter: Option<u8>,
+    pub column_map: ColumnMap,
+    pub threads: usize,
+    pub dedup: DedupPolicy,
+    pub strict: bool,
+    pub quarantine: bool,
+    pub save_snapshot: Option<PathBuf>,
+    pub load_snapshot: Option<PathBuf>,
+    pub save_index: Option<PathBuf>,
+    pub index: Option<PathBuf>,
+    pub lookup: Vec<u32>,
+    pub format: OutputFormat,
+    pub columns: Option<Vec<String>>,
+    pub query: Option<String>,
+    pub script: Option<PathBuf>,
+    pub show_help: bool,
+}
+
+impl Config {
+    // Command-line flags win over environment variables, which win over the
+    // default file names inside the data directory
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        Config::from_args_with_env(args, |name| env::var(name).ok())
+    }
+
+    // Same as from_args, with the environment read through `var`
+    pub fn from_args_with_env<I, E>(args: I, var: E) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut data_dir: Option<String> = None;
+        let mut players: Option<String> = None;
+        let mut ratings: Option<String> = None;
+        let mut tags: Option<String> = None;
+        let mut input_format: Option<String> = None;
+        let mut delimiter: Option<String> = None;
+        let mut column_map: Option<String> = None;
+        let mut threads: Option<String> = None;
+        let mut dedup: Option<String> = None;
+        let mut save_snapshot: Option<String> = None;
+        let mut load_snapshot: Option<String> = None;
+        let mut save_index: Option<String> = None;
+        let mut index: Option<String> = None;
+        let mut lookup: Option<String> = None;
+        let mut format: Option<String> = None;
+        let mut columns: Option<String> = None;
+        let mut query: Option<String> = None;
+        let mut script: Option<String> = None;
+        let mut strict = false;
+        let mut quarantine = false;
+        let mut show_help = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+
+            let slot = match flag.as_str() {
+                "--data-dir" => &mut data_dir,
+                "--players" => &mut players,
+                "--ratings" => &mut ratings,
+                "--tags" => &mut tags,
+                "--input-format" => &mut input_format,
+                "--delimiter" => &mut delimiter,
+                "--column-map" => &mut column_map,
+                "--threads" => &mut threads,
+                "--dedup" => &mut dedup,
+                "--save-snapshot" => &mut save_snapshot,
+                "--load-snapshot" => &mut load_snapshot,
+                "--save-index" => &mut save_index,
+                "--index" => &mut index,
+                "--lookup" => &mut lookup,
+                "--format" => &mut format,
+                "--columns" => &mut columns,
+                "--query" => &mut query,
+                "--script" => &mut script,
+                "--strict" => {
+                    strict = true;
+                    continue;
+                }
+                "--quarantine" => {
+                    quarantine = true;
+                    continue;
+                }
+                "--help" | "-h" => {
+                    show_help = true;
+                    continue;
+                }
+                _ => return Err(format!("Unknown argument '{}'\n\n{}", arg, USAGE)),
+            };
+
+            match inline_value.or_else(|| args.next()) {
+                Some(value) => *slot = Some(value),
+                None => return Err(format!("Missing value for {}", flag)),
+            }
+        }
+
+        let data_dir = PathBuf::from(
+            data_dir
+                .or_else(|| var("TF_CPD_DATA_DIR"))
+                .unwrap_or_else(|| ".".to_string()),
+        );
+
+        let threads = match threads.or_else(|| var("TF_CPD_THREADS")) {
+            Some(n) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(format!("Invalid thread count '{}'", n)),
+            },
+            None => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        };
+
+        let input_format = match input_format {
+            Some(format) => Some(format.parse::<InputFormat>()?),
+            None => None,
+        };
+
+        let delimiter = match delimiter.as_deref() {
+            None => None,
+            Some("\\t") | Some("tab") => Some(b'\t'),
+            Some(d) if d.len() == 1 => Some(d.as_bytes()[0]),
+            Some(d) => return Err(format!("Invalid delimiter '{}', expected one character", d)),
+        };
+
+        let column_map = match column_map.or_else(|| var("TF_CPD_COLUMN_MAP")) {
+            Some(path) => ColumnMap::load(&path).map_err(|e| format!("Invalid column map {}: {}", path, e))?,
+            None => ColumnMap::default(),
+        };
+
+        let dedup = match dedup.or_else(|| var("TF_CPD_DEDUP")) {
+            Some(policy) => policy.parse::<DedupPolicy>()?,
+            None => DedupPolicy::CountAll,
+        };
+
+        let format = match format.or_else(|| var("TF_CPD_FORMAT")) {
+            Some(format) => format.parse::<OutputFormat>()?,
+            None => OutputFormat::Table,
+        };
+
+        let columns = match columns {
+            Some(list) => {
+                let columns = parse_columns(&list)?;
+                select_columns(&player_columns(), &columns)?;
+                Some(columns)
+            }
+            None => None,
+        };
+
+        let lookup = match lookup {
+            Some(ids) => match ids.split(',').map(|id| id.trim().parse::<u32>()).collect() {
+                Ok(ids) => ids,
+                Err(_) => return Err(format!("Invalid sofifa id list '{}'", ids)),
+            },
+            None => Vec::new(),
+        };
+        if !lookup.is_empty() && index.is_none() {
+            return Err("--lookup needs --index".to_string());
+        }
+        if index.is_some() && lookup.is_empty() && query.is_none() && script.is_none() {
+            return Err("--index needs --lookup, --query or --script".to_string());
+        }
+        if query.is_some() && script.is_some() {
+            return Err("--query and --script cannot be used together".to_string());
+        }
+
+        Ok(Config {
+            players: resolve(players.or_else(|| var("TF_CPD_PLAYERS")), &data_dir, "players.csv"),
+            ratings: resolve(ratings.or_else(|| var("TF_CPD_RATINGS")), &data_dir, "rating.csv"),
+            tags: resolve(tags.or_else(|| var("TF_CPD_TAGS")), &data_dir, "tags.csv"),
+            input_format,
+            delimiter,
+            column_map,
+            threads,
+            dedup,
+            strict,
+            quarantine,
+            save_snapshot: save_snapshot.map(PathBuf::from),
+            load_snapshot: load_snapshot.map(PathBuf::from),
+            save_index: save_index.map(PathBuf::from),
+            index: index.map(PathBuf::from),
+            lookup,
+            format,
+            columns,
+            query,
+            script: script.map(PathBuf::from),
+            show_help,
+        })
+    }
+
+    // Batch runs keep stdout for command results
+    pub fn batch(&self) -> bool {
+        self.query.is_some() || self.script.is_some()
+    }
+
+    pub fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            strict: self.strict,
+            format: self.input_format,
+            delimiter: self.delimiter,
+            columns: self.column_map.clone(),
+        }
+    }
+
+    // Checks every input file up front so a bad path fails before loading
+    pub fn check_files(&self) -> Result<(), String> {
+        let files = [
+            ("players", &self.players, "--players", "TF_CPD_PLAYERS"),
+            ("ratings", &self.ratings, "--ratings", "TF_CPD_RATINGS"),
+            ("tags", &self.tags, "--tags", "TF_CPD_TAGS"),
+        ];
+
+        let missing: Vec<String> = files
+            .iter()
+            .filter(|(_, path, _, _)| !path.is_file())
+            .map(|(name, path, flag, var)| {
+                format!("{} file not found: {} (set {} or {})", name, path.display(), flag, var)
+            })
+            .collect();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing.join("\n"))
+        }
+    }
+}
+
+fn resolve(value: Option<String>, data_dir: &Path, default_name: &str) -> PathBuf {
+    match value {
+        Some(path) => PathBuf::from(path),
+        None => data_dir.join(default_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses `list` with only the variables in `env` set, whatever the
+    // environment the tests run in
+    fn parse(list: &[&str], env: &[(&str, &str)]) -> Result<Config, String> {
+        let args = list.iter().map(|s| s.to_string());
+        Config::from_args_with_env(args, |name| {
+            env.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn test_flags_override_data_dir() {
+        let config = parse(
+            &["--data-dir", "data", "--ratings=other/ratings.csv", "--threads", "3"],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(config.players, Path::new("data").join("players.csv"));
+        assert_eq!(config.ratings, PathBuf::from("other/ratings.csv"));
+        assert_eq!(config.threads, 3);
+        assert_eq!(config.columns, None);
+    }
+
+    #[test]
+    fn test_environment_below_flags() {
+        let env = [("TF_CPD_DATA_DIR", "env"), ("TF_CPD_TAGS", "env/tags.tsv"), ("TF_CPD_THREADS", "2")];
+        let config = parse(&["--threads", "5"], &env).unwrap();
+        assert_eq!(config.players, Path::new("env").join("players.csv"));
+        assert_eq!(config.tags, PathBuf::from("env/tags.tsv"));
+        assert_eq!(config.threads, 5);
+
+        let config = parse(&[], &[]).unwrap();
+        assert_eq!(config.players, Path::new(".").join("players.csv"));
+        assert_eq!(config.dedup, DedupPolicy::CountAll);
+        assert!(parse(&[], &[("TF_CPD_FORMAT", "yaml")]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(parse(&["--players"], &[]).is_err());
+        assert!(parse(&["--threads", "0"], &[]).is_err());
+        assert!(parse(&["--unknown"], &[]).is_err());
+        assert!(parse(&["--columns", "sofifa_id,shoe_size"], &[]).is_err());
+        assert!(parse(&["--query", "top10 ST", "--script", "-"], &[]).is_err());
+        assert!(parse(&["--lookup", "1"], &[]).is_err());
+        assert!(parse(&["--index", "players.idx"], &[]).is_err());
+        assert!(parse(&["--index", "players.idx", "--query", "show 1"], &[]).is_ok());
+    }
+}
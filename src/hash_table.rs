@@ -47,6 +47,20 @@ impl<K:Eq + Identifiable, V> HashMap <K, V> {
         total_length as f64 / non_empty_buckets.len() as f64
     }
 
+    pub fn modulo(&self) -> usize {
+        self.modulo
+    }
+
+    // Entries in bucket order; inserting them into a map with the same modulo
+    // rebuilds identical buckets
+    pub fn iter(&self) -> impl Iterator<Item = &(K, V)> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn into_entries(self) -> impl Iterator<Item = (K, V)> {
+        self.buckets.into_iter().flatten()
+    }
+
     pub fn hash(&self, key: &K) -> usize {
         (key.id() as usize) % self.modulo
     }
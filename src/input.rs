@@ -0,0 +1,307 @@
+use csv::StringRecord;
+use flate2::read::MultiGzDecoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::loader::csv_error_message;
+use crate::structs::Record;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    Tsv,
+    JsonLines,
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "tsv" => Ok(InputFormat::Tsv),
+            "jsonl" | "ndjson" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("Invalid input format '{}', expected csv, tsv or jsonl", s)),
+        }
+    }
+}
+
+// Source column names to struct field names, one section per kind of file:
+//
+//   { "players": { "player_id": "sofifa_id" },
+//     "ratings": { "player_id": "sofifa_id", "score": "rating" } }
+//
+// Columns that are not listed keep their own name.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColumnMap {
+    players: BTreeMap<String, String>,
+    ratings: BTreeMap<String, String>,
+    tags: BTreeMap<String, String>,
+}
+
+impl ColumnMap {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    fn section(&self, kind: &str) -> &BTreeMap<String, String> {
+        match kind {
+            "players" => &self.players,
+            "ratings" => &self.ratings,
+            _ => &self.tags,
+        }
+    }
+
+    // Field names of `columns` once mapped for the given kind of file
+    pub fn apply<'a>(&'a self, kind: &str, columns: &'a [String]) -> Vec<&'a str> {
+        let section = self.section(kind);
+        columns
+            .iter()
+            .map(|column| section.get(column).unwrap_or(column).as_str())
+            .collect()
+    }
+
+    // Renamed header row, or the list of required fields nothing maps to
+    pub fn map_headers<T: Record>(&self, headers: &StringRecord) -> Result<StringRecord, String> {
+        let columns: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+        let fields = self.apply(T::KIND, &columns);
+
+        let missing: Vec<&str> = T::REQUIRED
+            .iter()
+            .filter(|required| !fields.contains(required))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "no column for required {} field(s) {} (columns: {}); map them in the column map file",
+                T::KIND,
+                missing.join(", "),
+                columns.join(", ")
+            ));
+        }
+        Ok(StringRecord::from(fields))
+    }
+}
+
+// How input files are read. Anything left as None is worked out per file
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub strict: bool,
+    pub format: Option<InputFormat>,
+    pub delimiter: Option<u8>,
+    pub columns: ColumnMap,
+}
+
+impl ReadOptions {
+    // Explicit format first, then the extension ignoring a trailing .gz
+    pub fn format_of<P: AsRef<Path>>(&self, path: P) -> InputFormat {
+        if let Some(format) = self.format {
+            return format;
+        }
+
+        let path = path.as_ref();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("").to_lowercase();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+
+        match Path::new(name).extension().and_then(|e| e.to_str()) {
+            Some("tsv") | Some("tab") => InputFormat::Tsv,
+            Some("jsonl") | Some("ndjson") | Some("json") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+
+    // A custom delimiter only applies to CSV files, TSV is always tab separated
+    pub fn delimiter_of<P: AsRef<Path>>(&self, path: P) -> u8 {
+        match self.format_of(path) {
+            InputFormat::Tsv => b'\t',
+            _ => self.delimiter.unwrap_or(b','),
+        }
+    }
+}
+
+// A row that could not be turned into a record
+#[derive(Debug)]
+pub struct RecordError {
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl RecordError {
+    fn from_csv(error: csv::Error) -> Self {
+        RecordError {
+            line: error.position().map(|p| p.line()),
+            message: csv_error_message(&error),
+        }
+    }
+}
+
+// gzip is recognised by its magic bytes, whatever the file is called
+pub fn is_gzip<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0u8; 2];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| magic == [0x1f, 0x8b])
+        .unwrap_or(false)
+}
+
+pub fn open_input<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn Read>> {
+    let gzip = is_gzip(&path);
+    let reader = BufReader::new(File::open(&path)?);
+
+    if gzip {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+pub type Records<T> = Box<dyn Iterator<Item = Result<T, RecordError>>>;
+
+// Every record of a CSV, TSV or JSON Lines file, optionally gzip compressed,
+// deserialized into the same serde structs after the column map is applied
+pub fn open_records<P, T>(path: P, options: &ReadOptions) -> Result<Records<T>, String>
+where
+    P: AsRef<Path>,
+    T: DeserializeOwned + Record + 'static,
+{
+    let headers = StringRecord::from(input_fields(&path, options)?);
+    let mapped = options.columns.map_headers::<T>(&headers)?;
+    let input = open_input(&path).map_err(|e| e.to_string())?;
+
+    match options.format_of(&path) {
+        InputFormat::JsonLines => {
+            let renames: BTreeMap<String, String> = headers
+                .iter()
+                .zip(mapped.iter())
+                .filter(|(from, to)| from != to)
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .collect();
+
+            let lines = BufReader::new(input).lines().enumerate();
+            Ok(Box::new(lines.filter_map(move |(i, line)| {
+                let line_number = Some(i as u64 + 1);
+                let error = |message: String| RecordError {
+                    line: line_number,
+                    message,
+                };
+                match line {
+                    Ok(line) if line.trim().is_empty() => None,
+                    Ok(line) => Some(parse_json_line::<T>(&line, &renames).map_err(error)),
+                    Err(e) => Some(Err(error(e.to_string()))),
+                }
+            })))
+        }
+        InputFormat::Csv | InputFormat::Tsv => {
+            let mut rdr = csv::ReaderBuilder::new()
+                .delimiter(options.delimiter_of(&path))
+                .from_reader(input);
+            // Reads past the original header row before replacing it
+            rdr.headers().map_err(|e| e.to_string())?;
+            rdr.set_headers(mapped);
+            Ok(Box::new(
+                rdr.into_deserialize::<T>().map(|r| r.map_err(RecordError::from_csv)),
+            ))
+        }
+    }
+}
+
+fn parse_json_line<T: DeserializeOwned>(line: &str, renames: &BTreeMap<String, String>) -> Result<T, String> {
+    if renames.is_empty() {
+        return serde_json::from_str(line).map_err(|e| e.to_string());
+    }
+
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let value = match value {
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.into_iter()
+                .map(|(key, value)| match renames.get(&key) {
+                    Some(field) => (field.clone(), value),
+                    None => (key, value),
+                })
+                .collect(),
+        ),
+        other => other,
+    };
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+// Column names of a file: the header row, or the keys of the first JSON object
+pub fn input_fields<P: AsRef<Path>>(path: P, options: &ReadOptions) -> Result<Vec<String>, String> {
+    let input = open_input(&path).map_err(|e| e.to_string())?;
+
+    match options.format_of(&path) {
+        InputFormat::JsonLines => {
+            for line in BufReader::new(input).lines() {
+                let line = line.map_err(|e| e.to_string())?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value: serde_json::Value =
+                    serde_json::from_str(&line).map_err(|e| e.to_string())?;
+                return match value {
+                    serde_json::Value::Object(map) => Ok(map.keys().cloned().collect()),
+                    _ => Err("expected one JSON object per line".to_string()),
+                };
+            }
+            Ok(Vec::new())
+        }
+        InputFormat::Csv | InputFormat::Tsv => {
+            let mut rdr = csv::ReaderBuilder::new()
+                .delimiter(options.delimiter_of(&path))
+                .from_reader(input);
+            let headers = rdr.headers().map_err(|e| e.to_string())?;
+            Ok(headers.iter().map(|h| h.to_string()).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::RatingFile;
+
+    #[test]
+    fn test_format_detection_and_json_lines() {
+        let options = ReadOptions::default();
+        assert_eq!(options.format_of("ratings.tsv.gz"), InputFormat::Tsv);
+        assert_eq!(options.format_of("ratings.jsonl"), InputFormat::JsonLines);
+        assert_eq!(options.format_of("ratings.csv.gz"), InputFormat::Csv);
+        assert_eq!(options.delimiter_of("ratings.tsv"), b'\t');
+
+        let path = std::env::temp_dir().join(format!("tf_cpd_input_test_{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"user_id\": 1, \"sofifa_id\": 10, \"rating\": 4.5}\n\n{\"user_id\": 2}\n",
+        )
+        .unwrap();
+
+        let records: Vec<Result<RatingFile, RecordError>> = open_records(&path, &options).unwrap().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().sofifa_id, 10);
+        assert_eq!(records[1].as_ref().unwrap_err().line, Some(3));
+        assert_eq!(input_fields(&path, &options).unwrap().len(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_column_map() {
+        let columns: ColumnMap =
+            serde_json::from_str(r#"{"ratings": {"player_id": "sofifa_id", "score": "rating"}}"#).unwrap();
+
+        let headers = StringRecord::from(vec!["user_id", "player_id", "score"]);
+        let mapped = columns.map_headers::<RatingFile>(&headers).unwrap();
+        assert_eq!(mapped, StringRecord::from(vec!["user_id", "sofifa_id", "rating"]));
+
+        let error = ColumnMap::default().map_headers::<RatingFile>(&headers).unwrap_err();
+        assert!(error.contains("sofifa_id, rating"));
+
+        assert!(serde_json::from_str::<ColumnMap>(r#"{"rating": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,912 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::hash_table::HashMap;
+use crate::input::{input_fields, is_gzip, open_records, ColumnMap, InputFormat, ReadOptions};
+use crate::parallel::{aggregate_ratings_parallel, merge_partial_ratings};
+use crate::sort::external_merge_sort;
+use crate::structs::*;
+use crate::trie::Trie;
+
+const MAX_REPORTED_ERRORS: usize = 5;
+const EXTERNAL_SORT_THRESHOLD: u64 = 512 * 1024 * 1024; // bytes
+const EXTERNAL_SORT_RUN_SIZE: usize = 1_000_000; // records per sorted run
+
+// Made to use sofifa dataset with 22M ratings and 19k players infos
+pub struct Tables {
+    pub players_table: HashMap<u32, Player>,
+    pub rating_table: HashMap<u32, RatingPlayer>,
+    pub user_table: HashMap<u32, User>,
+    pub name_index: Trie,
+    pub tag_player: Trie,
+    pub position_player: Trie,
+    pub nationality_player: Trie,
+    pub club_player: Trie,
+    pub league_player: Trie,
+    pub histograms: HashMap<u32, RatingHistogram>,
+    pub player_tags: HashMap<u32, PlayerTags>,
+    // (user_id, sofifa_id) pairs already in the user table, so repeats are
+    // found without scanning the user's ratings. Built on the first lookup
+    // of a load or an ingest and dropped when it is done.
+    pub rated: Option<HashSet<u64>>,
+}
+
+fn pair_key(user_id: u32, sofifa_id: u32) -> u64 {
+    (user_id as u64) << 32 | sofifa_id as u64
+}
+
+impl Tables {
+    pub fn new() -> Self {
+        Tables {
+            players_table: HashMap::new(3_000), // +- 19k inseritons
+            rating_table: HashMap::new(3_000),  // +- 19k inseritons
+            user_table: HashMap::new(20_000),   // +- 140k inseritons
+            name_index: Trie::new(),
+            tag_player: Trie::new(),
+            position_player: Trie::new(),
+            nationality_player: Trie::new(),
+            club_player: Trie::new(),
+            league_player: Trie::new(),
+            histograms: HashMap::new(3_000),
+            player_tags: HashMap::new(3_000),
+            rated: None,
+        }
+    }
+
+    pub fn add_player(&mut self, record: Player) {
+        let temp = RatingPlayer {
+            sofifa_id: record.sofifa_id,
+            rating_sum: 0.0,
+            num_ratings: 0,
+        };
+
+        self.rating_table.insert(record.sofifa_id, temp);
+        self.histograms.insert(record.sofifa_id, RatingHistogram::new(record.sofifa_id));
+        self.name_index.insert_with_id(&record.long_name, record.id());
+        for position in record.positions() {
+            self.position_player.insert_with_id(position, record.id());
+        }
+        self.nationality_player.insert_with_id(&record.nationality, record.id());
+        self.club_player.insert_with_id(&record.club_name, record.id());
+        self.league_player.insert_with_id(&record.league_name, record.id());
+
+        self.players_table.insert(record.id(), record);
+    }
+
+    // Returns true when the user had already rated this player. Repeats are
+    // only looked for when the policy is not CountAll.
+    pub fn add_rating(
+        &mut self,
+        record: RatingFile,
+        dedup: DedupPolicy,
+        quarantine: &mut Quarantine,
+    ) -> bool {
+        let rating = match self.rating_table.search(&record.sofifa_id) {
+            Some(rating) => rating,
+            None => {
+                quarantine.add_ratings(RatingPlayer {
+                    sofifa_id: record.sofifa_id,
+                    rating_sum: record.rating,
+                    num_ratings: 1,
+                });
+                return false;
+            }
+        };
+
+        let key = pair_key(record.user_id, record.sofifa_id);
+        let repeated = dedup != DedupPolicy::CountAll && {
+            let user_table = &self.user_table;
+            let rated = self.rated.get_or_insert_with(|| {
+                user_table
+                    .iter()
+                    .flat_map(|(user_id, user)| user.ratings.iter().map(|r| pair_key(*user_id, r.sofifa_id)))
+                    .collect()
+            });
+            !rated.insert(key)
+        };
+
+        // Only a known repeat pays for the scan of the user's ratings
+        let previous = match (repeated, self.user_table.search(&record.user_id)) {
+            (true, Some(user)) => user
+                .ratings
+                .iter_mut()
+                .find(|previous| previous.sofifa_id == record.sofifa_id),
+            _ => None,
+        };
+
+        let histogram = self.histograms.search(&record.sofifa_id);
+
+        // The global aggregate and the histogram hold one value per user:
+        // their current rating
+        if let Some(previous) = previous {
+            let before = previous.average();
+            match dedup {
+                DedupPolicy::KeepLast => {
+                    rating.rating_sum += record.rating - before;
+                    previous.rating_sum = record.rating;
+                    previous.num_ratings = 1;
+                }
+                DedupPolicy::Average => {
+                    previous.add_rating(record.rating);
+                    rating.rating_sum += previous.average() - before;
+                }
+                DedupPolicy::KeepFirst | DedupPolicy::CountAll => return true,
+            }
+            if let Some(histogram) = histogram {
+                histogram.remove(before);
+                histogram.add(previous.average());
+            }
+            return true;
+        }
+
+        rating.add_rating(record.rating);
+        if let Some(histogram) = histogram {
+            histogram.add(record.rating);
+        }
+
+        let user_rating = RatingPlayer {
+            sofifa_id: record.sofifa_id,
+            rating_sum: record.rating,
+            num_ratings: 1,
+        };
+
+        match self.user_table.search(&record.user_id) {
+            Some(user) => user.add_rating(user_rating),
+            None => {
+                let user = User {
+                    user_id: record.user_id,
+                    ratings: vec![user_rating],
+                };
+                self.user_table.insert(user.user_id, user);
+            }
+        }
+        false
+    }
+
+    // Counts the current rating of one user, already deduplicated, in the
+    // player's aggregate and histogram
+    pub fn add_player_rating(&mut self, value: &RatingPlayer) {
+        if let Some(rating) = self.rating_table.search(&value.sofifa_id) {
+            rating.add_rating(value.average());
+        }
+        if let Some(histogram) = self.histograms.search(&value.sofifa_id) {
+            histogram.add(value.average());
+        }
+    }
+
+    pub fn add_tag(&mut self, record: Tags, quarantine: &mut Quarantine) {
+        if self.players_table.search_non_mut(&record.sofifa_id).is_some() {
+            self.tag_player.insert_with_id(&record.tag, record.sofifa_id);
+            match self.player_tags.search(&record.sofifa_id) {
+                Some(tags) => tags.add(&record.tag),
+                None => {
+                    let mut tags = PlayerTags::new(record.sofifa_id);
+                    tags.add(&record.tag);
+                    self.player_tags.insert(record.sofifa_id, tags);
+                }
+            }
+        } else {
+            quarantine.add_tag(record);
+        }
+    }
+}
+
+// Builds every table from the players, ratings and tags files
+pub fn load_tables(config: &Config) -> (Tables, Vec<LoadReport>, Quarantine) {
+    let mut tables = Tables::new();
+    let mut quarantine = Quarantine::new(config.quarantine);
+    let options = config.read_options();
+
+    let mut unknown_positions = 0;
+    let mut invalid_numbers = 0;
+    let mut players_report = read_csv(&config.players, &options, |record: PlayerRecord| {
+        let (player, invalid) = record.into_player();
+        unknown_positions += player.unknown_positions().len();
+        invalid_numbers += invalid;
+        tables.add_player(player);
+    });
+    players_report.unknown_positions = unknown_positions;
+    players_report.invalid_numbers = invalid_numbers;
+
+    let dedup = config.dedup;
+    let mut duplicates = 0;
+    let add_rating = |record: RatingFile| {
+        if tables.add_rating(record, dedup, &mut quarantine) {
+            duplicates += 1;
+        }
+    };
+
+    // Files that may not fit in memory are sorted on disk first, once per
+    // player and once per user (see read_ratings_sorted). Partial sums
+    // from the parallel loader cannot see repeats across chunks, so it is only
+    // used when every rating counts, and it splits raw bytes so the file has
+    // to be uncompressed CSV or TSV.
+    let splittable = options.format_of(&config.ratings) != InputFormat::JsonLines
+        && !is_gzip(&config.ratings);
+
+    let mut ratings_report = if file_len(&config.ratings) > EXTERNAL_SORT_THRESHOLD {
+        let (report, repeats) =
+            read_ratings_sorted(&config.ratings, &options, config.threads, dedup, &mut tables, &mut quarantine);
+        duplicates = repeats;
+        report
+    } else if config.threads > 1 && dedup == DedupPolicy::CountAll && splittable {
+        let (partials, report) = aggregate_ratings_parallel(
+            &config.ratings,
+            config.threads,
+            options.delimiter_of(&config.ratings),
+            &options.columns,
+            config.strict,
+        );
+        for partial in partials {
+            merge_partial_ratings(
+                partial,
+                &mut tables.rating_table,
+                &mut tables.histograms,
+                &mut tables.user_table,
+                &mut quarantine,
+            );
+        }
+        report
+    } else {
+        read_csv(&config.ratings, &options, add_rating)
+    };
+    tables.rated = None;
+    if dedup != DedupPolicy::CountAll {
+        ratings_report.duplicates = Some(duplicates);
+    }
+
+    let tags_report = read_csv(&config.tags, &options, |record: Tags| {
+        tables.add_tag(record, &mut quarantine);
+    });
+
+    (tables, vec![players_report, ratings_report, tags_report], quarantine)
+}
+
+// What happened while loading one file: how many data rows were seen, how
+// many of them were skipped, and the first few errors with their line numbers
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub file: String,
+    pub rows_read: usize,
+    pub rows_skipped: usize,
+    pub errors: Vec<String>,
+    pub fatal: Option<String>,
+    // Repeated (user_id, sofifa_id) ratings, None when they were not looked for
+    pub duplicates: Option<usize>,
+    // Position codes of the players file that are not known positions; the
+    // players are kept without them
+    pub unknown_positions: usize,
+    // Numeric cells of the players file holding something that is not a
+    // number; the players are kept with those fields empty
+    pub invalid_numbers: usize,
+}
+
+impl LoadReport {
+    pub fn new<P: AsRef<Path>>(file: P) -> Self {
+        LoadReport {
+            file: file.as_ref().display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.rows_skipped > 0 || self.fatal.is_some() || self.unknown_positions > 0 || self.invalid_numbers > 0
+    }
+
+    pub fn add_error<E: Display>(&mut self, line: Option<u64>, error: E) {
+        self.rows_skipped += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            match line {
+                Some(line) => self.errors.push(format!("line {}: {}", line, error)),
+                None => self.errors.push(error.to_string()),
+            }
+        }
+    }
+
+    // Folds the report of another chunk of the same file into this one
+    pub fn merge(&mut self, other: LoadReport) {
+        self.rows_read += other.rows_read;
+        self.rows_skipped += other.rows_skipped;
+        for error in other.errors {
+            if self.errors.len() < MAX_REPORTED_ERRORS {
+                self.errors.push(error);
+            }
+        }
+        if self.fatal.is_none() {
+            self.fatal = other.fatal;
+        }
+        if let Some(duplicates) = other.duplicates {
+            self.duplicates = Some(self.duplicates.unwrap_or(0) + duplicates);
+        }
+        self.unknown_positions += other.unknown_positions;
+        self.invalid_numbers += other.invalid_numbers;
+    }
+}
+
+// csv errors repeat the position in their message, the report already shows it
+pub fn csv_error_message(error: &csv::Error) -> String {
+    match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => match err.field() {
+            Some(field) => format!("field {}: {}", field + 1, err.kind()),
+            None => err.kind().to_string(),
+        },
+        csv::ErrorKind::UnequalLengths { expected_len, len, .. } => {
+            format!("expected {} fields, found {}", expected_len, len)
+        }
+        _ => error.to_string(),
+    }
+}
+
+// Streams every row of `filename` into `func`. Bad rows are recorded in the
+// report and skipped; in strict mode loading stops at the first one.
+pub fn read_csv<P, F, T>(filename: P, options: &ReadOptions, mut func: F) -> LoadReport
+where
+    P: AsRef<Path>,
+    F: FnMut(T),
+    T: DeserializeOwned + Record + 'static,
+{
+    let mut report = LoadReport::new(&filename);
+
+    let records = match open_records(&filename, options) {
+        Ok(records) => records,
+        Err(e) => {
+            report.fatal = Some(e);
+            return report;
+        }
+    };
+
+    for result in records {
+        report.rows_read += 1;
+        match result {
+            Ok(record) => func(record),
+            Err(e) => {
+                report.add_error(e.line, e.message);
+                if options.strict {
+                    break;
+                }
+            }
+        }
+    }
+    report
+}
+
+// Same as read_csv, but rows reach `func` in `compare` order after an
+// external merge sort
+pub fn read_csv_sorted<P, C, F, T>(
+    filename: P,
+    options: &ReadOptions,
+    run_size: usize,
+    threads: usize,
+    compare: C,
+    func: F,
+) -> LoadReport
+where
+    P: AsRef<Path>,
+    C: Fn(&T, &T) -> Ordering + Sync,
+    F: FnMut(T),
+    T: DeserializeOwned + Serialize + Record + Send + 'static,
+{
+    let mut report = LoadReport::new(&filename);
+
+    let records = match open_records(&filename, options) {
+        Ok(records) => records,
+        Err(e) => {
+            report.fatal = Some(e);
+            return report;
+        }
+    };
+
+    let mut row_report = LoadReport::new(&filename);
+    let records = records
+        .map_while(|result| {
+            row_report.rows_read += 1;
+            match result {
+                Ok(record) => Some(Some(record)),
+                Err(e) => {
+                    row_report.add_error(e.line, e.message);
+                    if options.strict {
+                        None
+                    } else {
+                        Some(None)
+                    }
+                }
+            }
+        })
+        .flatten()
+        .map(Ok::<T, csv::Error>);
+
+    let sorted = external_merge_sort(records, run_size, &env::temp_dir(), threads, compare, func);
+
+    report.merge(row_report);
+    if let Err(e) = sorted {
+        report.fatal = Some(e.to_string());
+    }
+    report
+}
+
+// Folds the ratings one user gave one player, which arrive next to each
+// other in a sorted stream, following the dedup policy. Under CountAll every
+// rating stands on its own.
+struct PairFolder {
+    dedup: DedupPolicy,
+    current: Option<(u32, RatingPlayer)>,
+    duplicates: usize,
+}
+
+impl PairFolder {
+    fn new(dedup: DedupPolicy) -> Self {
+        PairFolder {
+            dedup,
+            current: None,
+            duplicates: 0,
+        }
+    }
+
+    // Returns the user and value of the previous pair once `record` starts a
+    // new one
+    fn push(&mut self, record: RatingFile) -> Option<(u32, RatingPlayer)> {
+        if let Some((user_id, value)) = &mut self.current {
+            if self.dedup != DedupPolicy::CountAll
+                && *user_id == record.user_id
+                && value.sofifa_id == record.sofifa_id
+            {
+                self.duplicates += 1;
+                match self.dedup {
+                    DedupPolicy::KeepLast => {
+                        value.rating_sum = record.rating;
+                        value.num_ratings = 1;
+                    }
+                    DedupPolicy::Average => value.add_rating(record.rating),
+                    DedupPolicy::KeepFirst | DedupPolicy::CountAll => {}
+                }
+                return None;
+            }
+        }
+        let value = RatingPlayer {
+            sofifa_id: record.sofifa_id,
+            rating_sum: record.rating,
+            num_ratings: 1,
+        };
+        self.current.replace((record.user_id, value))
+    }
+
+    fn finish(&mut self) -> Option<(u32, RatingPlayer)> {
+        self.current.take()
+    }
+}
+
+// Ratings files too big for memory are sorted on disk twice. The per-player
+// ordering feeds the rating aggregates and histograms, the per-user ordering
+// builds one user at a time. Repeats by the same user are adjacent in both,
+// so they are folded in the stream; the count comes from the first pass.
+fn read_ratings_sorted<P: AsRef<Path>>(
+    filename: P,
+    options: &ReadOptions,
+    threads: usize,
+    dedup: DedupPolicy,
+    tables: &mut Tables,
+    quarantine: &mut Quarantine,
+) -> (LoadReport, usize) {
+    let mut folder = PairFolder::new(dedup);
+    let mut report = read_csv_sorted(
+        &filename,
+        options,
+        EXTERNAL_SORT_RUN_SIZE,
+        threads,
+        |a: &RatingFile, b: &RatingFile| a.sofifa_id.cmp(&b.sofifa_id).then(a.user_id.cmp(&b.user_id)),
+        |record: RatingFile| {
+            if tables.players_table.search_non_mut(&record.sofifa_id).is_none() {
+                quarantine.add_ratings(RatingPlayer {
+                    sofifa_id: record.sofifa_id,
+                    rating_sum: record.rating,
+                    num_ratings: 1,
+                });
+            } else if let Some((_, value)) = folder.push(record) {
+                tables.add_player_rating(&value);
+            }
+        },
+    );
+    if let Some((_, value)) = folder.finish() {
+        tables.add_player_rating(&value);
+    }
+    let duplicates = folder.duplicates;
+    if report.fatal.is_some() {
+        return (report, duplicates);
+    }
+
+    let mut folder = PairFolder::new(dedup);
+    let mut user: Option<User> = None;
+    let user_table = &mut tables.user_table;
+    let mut add_user_rating = |user_id: u32, value: RatingPlayer| {
+        if user.as_ref().map(|u| u.user_id) != Some(user_id) {
+            if let Some(done) = user.replace(User {
+                user_id,
+                ratings: Vec::new(),
+            }) {
+                user_table.insert(done.user_id, done);
+            }
+        }
+        if let Some(user) = &mut user {
+            user.add_rating(value);
+        }
+    };
+
+    let players_table = &tables.players_table;
+    let users_report = read_csv_sorted(
+        &filename,
+        options,
+        EXTERNAL_SORT_RUN_SIZE,
+        threads,
+        |a: &RatingFile, b: &RatingFile| a.user_id.cmp(&b.user_id).then(a.sofifa_id.cmp(&b.sofifa_id)),
+        |record: RatingFile| {
+            // Unknown players were already quarantined by the first pass
+            if players_table.search_non_mut(&record.sofifa_id).is_none() {
+                return;
+            }
+            if let Some((user_id, value)) = folder.push(record) {
+                add_user_rating(user_id, value);
+            }
+        },
+    );
+    if let Some((user_id, value)) = folder.finish() {
+        add_user_rating(user_id, value);
+    }
+    if let Some(done) = user {
+        tables.user_table.insert(done.user_id, done);
+    }
+
+    if users_report.fatal.is_some() {
+        report.fatal = users_report.fatal;
+    }
+    (report, duplicates)
+}
+
+pub struct IngestSummary {
+    pub kind: &'static str,
+    pub rows: usize,
+    pub orphans: usize,
+    pub players_changed: usize,
+    pub report: LoadReport,
+}
+
+// Streams an extra ratings or tags file into already built tables. The kind
+// of file is told apart by its columns: a `rating` column means ratings, a
+// `tag` column means tags.
+pub fn ingest_csv<P: AsRef<Path>>(
+    filename: P,
+    tables: &mut Tables,
+    dedup: DedupPolicy,
+    columns: &ColumnMap,
+    quarantine: &mut Quarantine,
+) -> Result<IngestSummary, String> {
+    // The format of an ingested file is always taken from its name
+    let options = ReadOptions {
+        columns: columns.clone(),
+        ..Default::default()
+    };
+    let headers = input_fields(&filename, &options)?;
+
+    let orphans_before = quarantine.orphan_ratings + quarantine.orphan_tags;
+
+    if columns.apply("ratings", &headers).contains(&"rating") {
+        // Averages before the first new rating of each touched player
+        let mut previous: HashMap<u32, f32> = HashMap::new(3_000);
+        let mut touched: Vec<u32> = Vec::new();
+
+        let mut duplicates = 0;
+
+        let mut report = read_csv(&filename, &options, |record: RatingFile| {
+            if previous.search_non_mut(&record.sofifa_id).is_none() {
+                if let Some(rating) = tables.rating_table.search_non_mut(&record.sofifa_id) {
+                    previous.insert(record.sofifa_id, rating.average());
+                    touched.push(record.sofifa_id);
+                }
+            }
+            if tables.add_rating(record, dedup, quarantine) {
+                duplicates += 1;
+            }
+        });
+        tables.rated = None;
+        if dedup != DedupPolicy::CountAll {
+            report.duplicates = Some(duplicates);
+        }
+
+        let players_changed = touched
+            .iter()
+            .filter(|id| {
+                let before = previous.search_non_mut(id);
+                let after = tables.rating_table.search_non_mut(id).map(|r| r.average());
+                before.copied() != after
+            })
+            .count();
+
+        let orphans = quarantine.orphan_ratings + quarantine.orphan_tags - orphans_before;
+        Ok(IngestSummary {
+            kind: "ratings",
+            rows: report.rows_read - report.rows_skipped - orphans,
+            orphans,
+            players_changed,
+            report,
+        })
+    } else if columns.apply("tags", &headers).contains(&"tag") {
+        let report = read_csv(&filename, &options, |record: Tags| {
+            tables.add_tag(record, quarantine);
+        });
+
+        let orphans = quarantine.orphan_ratings + quarantine.orphan_tags - orphans_before;
+        Ok(IngestSummary {
+            kind: "tags",
+            rows: report.rows_read - report.rows_skipped - orphans,
+            orphans,
+            players_changed: 0,
+            report,
+        })
+    } else {
+        Err("expected a ratings file (rating column) or a tags file (tag column)".to_string())
+    }
+}
+
+pub fn file_len<P: AsRef<Path>>(filename: P) -> u64 {
+    fs::metadata(filename).map(|m| m.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Tags;
+
+    #[test]
+    fn test_read_csv_reports_bad_rows() {
+        let path = env::temp_dir().join(format!("tf_cpd_loader_test_{}.csv", std::process::id()));
+        fs::write(&path, "user_id,sofifa_id,tag\n1,10,Fast\nx,11,Slow\n2,12\n3,13,Strong\n").unwrap();
+
+        let mut tags = Vec::new();
+        let report = read_csv(&path, &ReadOptions::default(), |record: Tags| tags.push(record.tag));
+        assert_eq!(tags, vec!["Fast", "Strong"]);
+        assert_eq!(report.rows_read, 4);
+        assert_eq!(report.rows_skipped, 2);
+        assert!(report.errors[0].starts_with("line 3:"));
+        assert!(report.errors[1].starts_with("line 4:"));
+
+        let mut count = 0;
+        let strict = ReadOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let report = read_csv(&path, &strict, |_: Tags| count += 1);
+        assert_eq!(count, 1);
+        assert_eq!(report.rows_skipped, 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_players_with_optional_columns() {
+        let path = env::temp_dir().join(format!("tf_cpd_players_test_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "sofifa_id,short_name,long_name,player_positions,nationality,club_name,league_name,age,overall,value_eur,preferred_foot,pace\n\
+             1,A,Alpha,ST,Brazil,Club,League,31,93,67500000,Left,85\n\
+             2,B,Beta,GK,Spain,Club,League,,88.0,1.05e+08,Right,\n\
+             3,C,Gamma,CB,Italy,Club,League,n/a,true,-5,Right,70.4\n",
+        )
+        .unwrap();
+
+        let mut players = Vec::new();
+        let mut invalid_numbers = 0;
+        let report = read_csv(&path, &ReadOptions::default(), |record: PlayerRecord| {
+            let (player, invalid) = record.into_player();
+            players.push(player);
+            invalid_numbers += invalid;
+        });
+        assert_eq!(report.rows_skipped, 0);
+        assert_eq!(invalid_numbers, 3);
+        assert_eq!(players[0].age, Some(31));
+        assert_eq!(players[0].value_eur, Some(67500000));
+        assert_eq!(players[0].preferred_foot.as_deref(), Some("Left"));
+        assert_eq!(players[1].age, None);
+        assert_eq!(players[1].overall, Some(88));
+        assert_eq!(players[1].value_eur, Some(105000000));
+        assert_eq!(players[1].pace, None);
+        assert_eq!(players[1].potential, None);
+        assert_eq!(players[2].age, None);
+        assert_eq!(players[2].overall, None);
+        assert_eq!(players[2].value_eur, None);
+        assert_eq!(players[2].pace, Some(70));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_histogram_and_tags_follow_ratings() {
+        let mut tables = Tables::new();
+        let mut quarantine = Quarantine::new(false);
+        tables.add_player(Player {
+            sofifa_id: 10,
+            long_name: "Alpha".to_string(),
+            player_positions: "ST".to_string(),
+            ..Default::default()
+        });
+
+        let rate = |user_id, rating| RatingFile {
+            user_id,
+            sofifa_id: 10,
+            rating,
+        };
+        tables.add_rating(rate(1, 4.0), DedupPolicy::KeepLast, &mut quarantine);
+        tables.add_rating(rate(2, 4.0), DedupPolicy::KeepLast, &mut quarantine);
+        // User 1 changes their mind, only their last rating counts
+        tables.add_rating(rate(1, 2.5), DedupPolicy::KeepLast, &mut quarantine);
+
+        let histogram = tables.histograms.search_non_mut(&10).unwrap();
+        assert_eq!(histogram.buckets[RatingHistogram::bucket(4.0)], 1);
+        assert_eq!(histogram.buckets[RatingHistogram::bucket(2.5)], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u32>(), 2);
+
+        for (user_id, tag) in [(1, "Fast"), (2, "Strong"), (3, "fast")] {
+            let tag = Tags {
+                user_id,
+                sofifa_id: 10,
+                tag: tag.to_string(),
+            };
+            tables.add_tag(tag, &mut quarantine);
+        }
+        let tags = tables.player_tags.search_non_mut(&10).unwrap();
+        assert_eq!(tags.top(5), vec![("Fast", 2), ("Strong", 1)]);
+    }
+
+    #[test]
+    fn test_repeats_found_in_prebuilt_user_table() {
+        let mut tables = Tables::new();
+        tables.add_player(Player {
+            sofifa_id: 10,
+            ..Default::default()
+        });
+        // As after a snapshot or the parallel loader: no add_rating call has
+        // seen these ratings
+        tables.rating_table.search(&10).unwrap().add_rating(2.0);
+        let user = User {
+            user_id: 1,
+            ratings: vec![RatingPlayer {
+                sofifa_id: 10,
+                rating_sum: 2.0,
+                num_ratings: 1,
+            }],
+        };
+        tables.user_table.insert(1, user);
+
+        let mut quarantine = Quarantine::new(false);
+        let record = RatingFile {
+            user_id: 1,
+            sofifa_id: 10,
+            rating: 4.0,
+        };
+        assert!(tables.add_rating(record.clone(), DedupPolicy::KeepLast, &mut quarantine));
+        assert_eq!(tables.rating_table.search_non_mut(&10).unwrap().average(), 4.0);
+        assert_eq!(tables.user_table.search_non_mut(&1).unwrap().ratings.len(), 1);
+
+        let other = RatingFile { user_id: 2, ..record };
+        assert!(!tables.add_rating(other, DedupPolicy::KeepLast, &mut quarantine));
+    }
+
+    #[test]
+    fn test_ingest_drops_rated_pairs() {
+        let mut tables = Tables::new();
+        tables.add_player(Player {
+            sofifa_id: 10,
+            ..Default::default()
+        });
+        let path = env::temp_dir().join(format!("tf_cpd_ingest_test_{}.csv", std::process::id()));
+        fs::write(&path, "user_id,sofifa_id,rating\n1,10,2.0\n1,10,4.0\n2,10,3.0\n").unwrap();
+
+        let mut quarantine = Quarantine::new(false);
+        let summary =
+            ingest_csv(&path, &mut tables, DedupPolicy::KeepLast, &ColumnMap::default(), &mut quarantine).unwrap();
+        assert_eq!(summary.report.duplicates, Some(1));
+        assert_eq!(tables.rating_table.search_non_mut(&10).unwrap().average(), 3.5);
+        assert!(tables.rated.is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_positions_are_normalized() {
+        let player = Player {
+            sofifa_id: 7,
+            player_positions: " st, Lw ,XX".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(player.positions(), vec!["ST", "LW"]);
+        assert_eq!(player.unknown_positions(), vec!["XX"]);
+
+        let mut tables = Tables::new();
+        tables.add_player(player);
+        assert_eq!(tables.position_player.get_id("ST"), Some(vec![7]));
+        assert_eq!(tables.position_player.get_id("LW"), Some(vec![7]));
+        assert_eq!(tables.position_player.get_id("XX"), None);
+    }
+
+    #[test]
+    fn test_sorted_ratings_match_unsorted() {
+        let rows = [(1, 10, 4.0), (2, 11, 3.0), (1, 10, 2.0), (3, 99, 1.0), (2, 10, 5.0), (1, 11, 0.5), (1, 10, 3.0)];
+        let path = env::temp_dir().join(format!("tf_cpd_sorted_test_{}.csv", std::process::id()));
+        let mut csv = "user_id,sofifa_id,rating\n".to_string();
+        for (user_id, sofifa_id, rating) in rows {
+            csv.push_str(&format!("{},{},{}\n", user_id, sofifa_id, rating));
+        }
+        fs::write(&path, csv).unwrap();
+
+        let with_players = || {
+            let mut tables = Tables::new();
+            for sofifa_id in [10, 11] {
+                tables.add_player(Player {
+                    sofifa_id,
+                    ..Default::default()
+                });
+            }
+            tables
+        };
+
+        for dedup in [DedupPolicy::KeepFirst, DedupPolicy::KeepLast, DedupPolicy::Average, DedupPolicy::CountAll] {
+            let mut expected = with_players();
+            let mut quarantine = Quarantine::new(false);
+            let mut repeats = 0;
+            for (user_id, sofifa_id, rating) in rows {
+                let record = RatingFile {
+                    user_id,
+                    sofifa_id,
+                    rating,
+                };
+                if expected.add_rating(record, dedup, &mut quarantine) {
+                    repeats += 1;
+                }
+            }
+
+            let mut tables = with_players();
+            let mut sorted_quarantine = Quarantine::new(false);
+            let (report, duplicates) =
+                read_ratings_sorted(&path, &ReadOptions::default(), 1, dedup, &mut tables, &mut sorted_quarantine);
+            assert!(report.fatal.is_none());
+            if dedup != DedupPolicy::CountAll {
+                assert_eq!(duplicates, repeats, "{:?}", dedup);
+            }
+            assert_eq!(sorted_quarantine.orphan_ratings, quarantine.orphan_ratings);
+
+            for sofifa_id in [10, 11] {
+                let rating = |tables: &Tables| {
+                    let rating = tables.rating_table.search_non_mut(&sofifa_id).unwrap();
+                    (rating.num_ratings, rating.average())
+                };
+                assert_eq!(rating(&tables), rating(&expected), "{:?}", dedup);
+                assert_eq!(
+                    tables.histograms.search_non_mut(&sofifa_id).unwrap().buckets,
+                    expected.histograms.search_non_mut(&sofifa_id).unwrap().buckets
+                );
+            }
+            for user_id in [1, 2] {
+                let ratings = |tables: &Tables| {
+                    let mut ratings: Vec<(u32, f32)> = tables
+                        .user_table
+                        .search_non_mut(&user_id)
+                        .unwrap()
+                        .ratings
+                        .iter()
+                        .map(|r| (r.sofifa_id, r.average()))
+                        .collect();
+                    ratings.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+                    ratings
+                };
+                assert_eq!(ratings(&tables), ratings(&expected), "{:?}", dedup);
+            }
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
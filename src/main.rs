@@ -1,245 +1,282 @@
-use regex::Regex;
-use serde::de::DeserializeOwned;
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::{stdin, stdout, Write};
+use std::env;
+use std::fs;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::io::{stdin, IsTerminal, Read};
 use std::path::Path;
+use std::process;
 use std::time::{Duration, Instant};
 
+mod commands;
+mod completion;
+mod config;
 mod hash_table;
+mod input;
+mod loader;
+mod mmap_index;
+mod output;
+mod parallel;
+mod positions;
 mod sort;
 mod structs;
 mod trie;
 mod prints;
-
-use hash_table::*;
-use structs::Identifiable;
-use structs::*;
+mod query;
+mod similar;
+mod snapshot;
+
+use commands::*;
+use completion::*;
+use config::Config;
+use loader::*;
+use mmap_index::*;
+use output::*;
+use structs::Quarantine;
 use prints::*;
-use trie::*;
-
-use sort::*;
+use snapshot::*;
 
 #[allow(dead_code)]
 #[allow(unused_variables)]
 fn main() {
-    // Made to use sofifa dataset with 22M ratings and 19k players infos
-    let mut rating_table: HashMap<u32, RatingPlayer> = HashMap::new(3_000); // +- 19k inseritons
-    let mut players_table: HashMap<u32, Player> = HashMap::new(3_000); // +- 19k inseritons
-    let mut user_table: HashMap<u32, User> = HashMap::new(20_000); // +- 140k inseritons
-
-    let mut name_index = Trie::new();
-    let mut tag_player = Trie::new();
-    let mut position_player = Trie::new();
-
-    let start = Instant::now();
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
 
-    let x = read_csv("players.csv", |record: Player| {
-        players_table.insert(record.id(), record.clone());
+    if config.show_help {
+        println!("{}", config::USAGE);
+        return;
+    }
 
-        let temp = RatingPlayer {
-            sofifa_id: record.sofifa_id,
-            rating_sum: 0.0,
-            num_ratings: 0,
+    // Answered from the mapped index alone, nothing else is loaded
+    if let Some(path) = &config.index {
+        let index = match MappedIndex::open(path) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("Could not open index {}: {}", path.display(), e);
+                process::exit(1);
+            }
         };
 
-        rating_table.insert(record.sofifa_id, temp);
-        name_index.insert_with_id(&record.long_name, record.id());
-        let all_positons: Vec<String> = record
-            .player_positions
-            .split(",")
-            .map(|s| s.trim()) // Remove leading and trailing whitespace
-            .map(|s| s.replace(" ", "")) // Remove spaces within the strings
-            .collect();
-
-        for positions in all_positons {
-            position_player.insert_with_id(&positions, record.id());
-        }
-    });
-
-    let x = read_csv("rating.csv", |record: RatingFile| {
-        match user_table.search(&record.user_id) {
-            Some(user) => {
-                user.ratings.push(RatingPlayer {
-                    sofifa_id: record.sofifa_id,
-                    rating_sum: record.rating,
-                    num_ratings: 1,
-                });
-            }
-            None => {
-                let user = User {
-                    user_id: record.user_id,
-                    ratings: vec![RatingPlayer {
-                        sofifa_id: record.sofifa_id,
-                        rating_sum: record.rating,
-                        num_ratings: 1,
-                    }],
-                };
-                user_table.insert(user.user_id, user.clone());
+        if !config.lookup.is_empty() {
+            let columns: Vec<&str> = match &config.columns {
+                Some(columns) => columns.iter().map(|c| c.as_str()).collect(),
+                None => TOP_COLUMNS.to_vec(),
+            };
+            if let Err(e) = print_indexed_players(&index, &config.lookup, &columns, config.format) {
+                eprintln!("{}", e);
+                process::exit(2);
             }
+            return;
         }
 
-        let mut rating = rating_table.search(&record.sofifa_id);
-        rating.as_mut().unwrap().add_rating(record.rating);
-    });
-
-    let x = read_csv("tags.csv", |record: Tags| {
-        tag_player.insert_with_id(&record.tag, record.sofifa_id);
-    });
+        // --query or --script, with lookups served by the index
+        let mut tables = Tables::new();
+        let mut quarantine = Quarantine::new(false);
+        let mut session = Session::new(&config);
+        let mut ctx = Context {
+            tables: &mut tables,
+            quarantine: &mut quarantine,
+            config: &config,
+            session: &mut session,
+            index: Some(&index),
+        };
+        run_batch(&config, &mut ctx);
+        return;
+    }
 
-    let duration = start.elapsed();
+    if let Err(e) = config.check_files() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    print_table_statistics(duration, &players_table, &rating_table, &user_table);
+    let threads = config.threads;
+    let batch = config.batch();
 
-    let mut s = String::new();
+    let start = Instant::now();
 
-    while s != "n" {
-        s.clear();
-        print!("> ");
-        let _ = stdout().flush();
-        stdin()
-            .read_line(&mut s)
-            .expect("Did not enter a correct string");
-        if let Some('\n') = s.chars().next_back() {
-            s.pop();
-        }
-        if let Some('\r') = s.chars().next_back() {
-            s.pop();
+    let snapshot = config.load_snapshot.as_ref().and_then(|path| {
+        match load_snapshot(path, &config) {
+            Ok(loaded) => Some(loaded),
+            Err(e) => {
+                eprintln!(
+                    "Snapshot {} not used ({}), loading CSV files",
+                    path.display(),
+                    e
+                );
+                None
+            }
         }
-        let mut words: Vec<&str> = parse_string(&s);
+    });
 
-        if words.len() < 2 {
-            println!("Insufficient arguments");
-            words.clear();
+    let (mut tables, mut quarantine) = match snapshot {
+        Some((tables, quarantine)) => {
+            if !batch {
+                let path = config.load_snapshot.as_ref().unwrap();
+                note(config.format, &format!("Loaded snapshot {}", path.display()));
+            }
+            (tables, quarantine)
         }
+        None => {
+            let (tables, reports, quarantine) = load_tables(&config);
 
-        match (words[0].to_lowercase()).as_str() {
-            "player" => {
-                let arg: String = remove_outer_quotes(&words[1..words.len()].join(" "));
-                get_player_start_with(&arg, &mut name_index, &players_table, &rating_table);
+            if !batch || reports.iter().any(|report| report.has_errors()) {
+                print_load_report(&reports, config.format, batch);
+                print_quarantine_summary(&quarantine, config.format, batch);
             }
-            "user" => {
-                let id: Result<u32, std::num::ParseIntError> =
-                    remove_outer_quotes(words[1]).parse::<u32>();
-
-                if id.is_err() {
-                    println!("Invalid user id");
-                }
-                if id.is_ok() {
 
-                    if let Some(user) = &mut user_table.search(&id.unwrap()) {
-                        let user_rating: &mut Vec<RatingPlayer> = &mut user.ratings;
-                        sort_user_ratings(user_rating);
-                        sort_user_global_ratings(user_rating, &rating_table);
-    
-                        print_player_and_rating_with_lim(
-                            user_rating,
-                            &players_table,
-                            &rating_table,
-                            20,
-                        );
-                    }else {
-                        println!("User does not exist");
-                    }
-
-                }
+            if config.strict && reports.iter().any(|report| report.has_errors()) {
+                eprintln!("Errors while loading data, aborting (--strict)");
+                process::exit(1);
             }
-            "tags" => {
-                let mut players: Vec<_> = tag_player
-                    .get_id(&remove_outer_quotes(words[1]))
-                    .unwrap_or_default();
+            (tables, quarantine)
+        }
+    };
 
-                for tag in 2..words.len() {
-                    if let Some(search) = tag_player.get_id(&remove_outer_quotes(words[tag])) {
-                        players.retain(|id| search.contains(id));
-                    }
-                }
+    let duration = start.elapsed();
 
-                let mut user_rating: Vec<RatingPlayer> = Vec::new();
+    if let Some(path) = &config.save_snapshot {
+        match save_snapshot(path, &tables, &quarantine, &config) {
+            Ok(()) => eprintln!("Saved snapshot {}", path.display()),
+            Err(e) => eprintln!("Could not save snapshot {}: {}", path.display(), e),
+        }
+    }
 
-                for player in players {
-                    if let Some(n) = rating_table.search_non_mut(&player) {
-                        user_rating.push(n.clone());
-                    }
-                }
+    if let Some(path) = &config.save_index {
+        match write_index(path, &tables.players_table, &tables.rating_table) {
+            Ok(()) => eprintln!("Saved index {}", path.display()),
+            Err(e) => eprintln!("Could not save index {}: {}", path.display(), e),
+        }
+    }
 
-                sort_user_ratings(&mut user_rating);
-                print_player_and_rating(&user_rating, &players_table, &rating_table);
-            }
-            _ if words[0].to_lowercase().starts_with("top") => {
-                let number_part = &words[0][3..];
+    if !batch {
+        print_table_statistics(
+            duration,
+            threads,
+            &tables.players_table,
+            &tables.rating_table,
+            &tables.user_table,
+            config.format,
+        );
+    }
 
-                let mut top_num = 0;
+    let mut session = Session::new(&config);
+    let mut ctx = Context {
+        tables: &mut tables,
+        quarantine: &mut quarantine,
+        config: &config,
+        session: &mut session,
+        index: None,
+    };
+
+    if batch {
+        run_batch(&config, &mut ctx);
+        return;
+    }
 
-                match number_part.parse::<u32>() {
-                    Ok(number) => {
-                        top_num = number;
-                    }
-                    Err(_) => println!("Invalid top number"),
-                }
+    let mut editor: Editor<ReplHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Could not start the prompt: {}", e);
+            process::exit(1);
+        }
+    };
+    editor.set_helper(Some(ReplHelper::new(ctx.tables)));
 
-                let position = remove_outer_quotes(words[1]);
-                let players_in_position = position_player.get_id(&position);
+    // Only an interactive session reads and writes the history file
+    let history = history_path().filter(|_| stdin().is_terminal());
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
 
-                let mut players_position_ratings = Vec::new();
+    while !ctx.session.quit {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl-C drops the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            // End of input, e.g. a pipe that ran out of commands
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Could not read command: {}", e);
+                process::exit(1);
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
 
-                match players_in_position {
-                    Some(player_position) => {
-                        for player in player_position {
-                            let rating = rating_table.search_non_mut(&player).unwrap();
-                            if rating.num_ratings >= 1000 {
-                                players_position_ratings.push(rating);
-                            }
-                        }
-                        sort_players_by_rating(&mut players_position_ratings);
-                        print_player_and_rating_long(
-                            &players_position_ratings,
-                            top_num,
-                            &players_table,
-                        )
-                    }
-                    None => {
-                        println!("No players in position {}", position);
-                    }
-                }
+        if let Err(e) = run_command(&line, &mut ctx) {
+            note(ctx.session.format, &e);
+        }
+        if line.trim_start().to_lowercase().starts_with("ingest") {
+            if let Some(helper) = editor.helper_mut() {
+                helper.refresh_tags(ctx.tables);
             }
-            _ => println!("Invalid"),
         }
     }
-}
 
-fn parse_string(input: &str) -> Vec<&str> {
-    let re = Regex::new(r"'([^']*)'|\S+").unwrap();
-    let mut result = Vec::new();
-
-    for cap in re.captures_iter(input) {
-        if let Some(matched) = cap.get(1) {
-            result.push(matched.as_str());
-        } else {
-            result.push(cap.get(0).unwrap().as_str());
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("Could not save history {}: {}", path.display(), e);
         }
     }
-
-    result
 }
 
-fn read_csv<P, F, T>(filename: P, mut func: F) -> Result<(), Box<dyn Error>>
-where
-    P: AsRef<Path>,
-    F: FnMut(T),
-    T: DeserializeOwned,
-{
-    let file = File::open(filename)?;
-    let reader = BufReader::new(file);
+// Runs --query or --script, exiting with 1 when a command failed
+fn run_batch(config: &Config, ctx: &mut Context) {
+    if let Some(query) = &config.query {
+        if let Err(e) = run_command(query, ctx) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
 
-    let mut rdr = csv::Reader::from_reader(reader);
+    if let Some(path) = &config.script {
+        let failed = match run_script(path, ctx) {
+            Ok(failed) => failed,
+            Err(e) => {
+                eprintln!("Could not read script {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        };
+        if failed > 0 {
+            eprintln!("{} command(s) failed", failed);
+            process::exit(1);
+        }
+    }
+}
 
-    for result in rdr.deserialize() {
-        let record: T = result?;
-        func(record);
+// Runs every line of a script ("-" reads stdin), skipping blank lines and
+// # comments, and stopping at quit like the prompt does. Failed commands are
+// reported on stderr and counted, the rest of the script still runs.
+fn run_script(path: &Path, ctx: &mut Context) -> std::io::Result<usize> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(path)?
+    };
+
+    let mut failed = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(e) = run_command(line, ctx) {
+            eprintln!("{}:{}: {}: {}", path.display(), i + 1, line, e);
+            failed += 1;
+        }
+        if ctx.session.quit {
+            break;
+        }
     }
-    Ok(())
+    Ok(failed)
 }
 
 #[allow(dead_code)]
@@ -253,36 +290,3 @@ where
     //println!("Tempo gasto: {:?}", duration);
     duration
 }
-
-fn remove_outer_quotes(s: &str) -> String {
-    s.trim_matches(|c| c == '"' || c == '\'').to_string()
-}
-
-pub fn get_player_start_with(
-    prefix: &str,
-    trie: &mut Trie,
-    players_table: &HashMap<u32, Player>,
-    rating_table: &HashMap<u32, RatingPlayer>,
-) -> Option<Vec<Player>> {
-    let mut ratings = Vec::<RatingPlayer>::new();
-
-    for name in trie.get_words_starting_with(prefix) {
-        match trie.get_id(&name) {
-            Some(n) => {
-                for i in n {
-                    if let Some(rate) = rating_table.search_non_mut(&i) {
-                        ratings.push(rate.clone());
-                    }
-                }
-            }
-            None => {
-                println!("No match found for {}", name);
-            }
-        }
-    }
-    sort_user_ratings(&mut ratings);
-
-    print_player_and_rating_with_positon(&ratings, players_table);
-
-    None
-}
@@ -0,0 +1,273 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::hash_table::HashMap;
+use crate::sort::merge_sort;
+use crate::structs::*;
+
+// Flat index file, read straight from a memory mapping.
+//
+// Layout (little endian):
+//   header:   magic "TFCPDIDX" | version u32 | count u32
+//   ratings:  count fixed-size records (sofifa_id u32, rating_sum f32,
+//             num_ratings u32), sorted by sofifa_id
+//   offsets:  count rows of PLAYER_FIELDS + 1 u32 offsets into the strings
+//             section, row i belongs to rating record i
+//   strings:  the Player text fields, concatenated
+const MAGIC: &[u8; 8] = b"TFCPDIDX";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RATING_SIZE: usize = 12;
+const PLAYER_FIELDS: usize = 6;
+const OFFSETS_SIZE: usize = (PLAYER_FIELDS + 1) * 4;
+
+fn player_fields(player: &Player) -> [&str; PLAYER_FIELDS] {
+    [
+        &player.short_name,
+        &player.long_name,
+        &player.player_positions,
+        &player.nationality,
+        &player.club_name,
+        &player.league_name,
+    ]
+}
+
+pub fn write_index<P: AsRef<Path>>(
+    path: P,
+    players_table: &HashMap<u32, Player>,
+    rating_table: &HashMap<u32, RatingPlayer>,
+) -> std::io::Result<()> {
+    let players: Vec<&Player> = players_table.iter().map(|(_, player)| player).collect();
+    let players = merge_sort(players, &mut |a: &&Player, b: &&Player| a.sofifa_id.cmp(&b.sofifa_id));
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&VERSION.to_le_bytes())?;
+    file.write_all(&(players.len() as u32).to_le_bytes())?;
+
+    for player in &players {
+        let (rating_sum, num_ratings) = match rating_table.search_non_mut(&player.sofifa_id) {
+            Some(rating) => (rating.rating_sum, rating.num_ratings),
+            None => (0.0, 0),
+        };
+        file.write_all(&player.sofifa_id.to_le_bytes())?;
+        file.write_all(&rating_sum.to_le_bytes())?;
+        file.write_all(&num_ratings.to_le_bytes())?;
+    }
+
+    let mut offset: u32 = 0;
+    for player in &players {
+        file.write_all(&offset.to_le_bytes())?;
+        for field in player_fields(player) {
+            offset += field.len() as u32;
+            file.write_all(&offset.to_le_bytes())?;
+        }
+    }
+
+    for player in &players {
+        for field in player_fields(player) {
+            file.write_all(field.as_bytes())?;
+        }
+    }
+
+    file.flush()
+}
+
+// Player text fields borrowed from the mapping
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerRef<'a> {
+    pub short_name: &'a str,
+    pub long_name: &'a str,
+    pub player_positions: &'a str,
+    pub nationality: &'a str,
+    pub club_name: &'a str,
+    pub league_name: &'a str,
+}
+
+impl<'a> From<&'a Player> for PlayerRef<'a> {
+    fn from(player: &'a Player) -> Self {
+        PlayerRef {
+            short_name: &player.short_name,
+            long_name: &player.long_name,
+            player_positions: &player.player_positions,
+            nationality: &player.nationality,
+            club_name: &player.club_name,
+            league_name: &player.league_name,
+        }
+    }
+}
+
+pub struct MappedIndex {
+    mmap: Mmap,
+    count: usize,
+}
+
+impl MappedIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        // The file is only read, and it is not expected to change while mapped
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| e.to_string())?;
+
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            return Err("not an index file".to_string());
+        }
+        if read_u32(&mmap, 8) != VERSION {
+            return Err("unsupported index version".to_string());
+        }
+
+        let count = read_u32(&mmap, 12) as usize;
+        let index = MappedIndex { mmap, count };
+        if index.mmap.len() < index.strings_start() {
+            return Err("index file is truncated".to_string());
+        }
+
+        let strings_len = if count > 0 {
+            index.offset(count - 1, PLAYER_FIELDS) as usize
+        } else {
+            0
+        };
+        if index.mmap.len() < index.strings_start() + strings_len {
+            return Err("index file is truncated".to_string());
+        }
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    fn offsets_start(&self) -> usize {
+        HEADER_SIZE + self.count * RATING_SIZE
+    }
+
+    fn strings_start(&self) -> usize {
+        self.offsets_start() + self.count * OFFSETS_SIZE
+    }
+
+    fn sofifa_id_at(&self, i: usize) -> u32 {
+        read_u32(&self.mmap, HEADER_SIZE + i * RATING_SIZE)
+    }
+
+    fn offset(&self, i: usize, field: usize) -> u32 {
+        read_u32(&self.mmap, self.offsets_start() + i * OFFSETS_SIZE + field * 4)
+    }
+
+    // Binary search over the sorted rating records
+    fn position(&self, sofifa_id: u32) -> Option<usize> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.sofifa_id_at(mid).cmp(&sofifa_id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn rating(&self, sofifa_id: u32) -> Option<RatingPlayer> {
+        let i = self.position(sofifa_id)?;
+        let start = HEADER_SIZE + i * RATING_SIZE;
+        Some(RatingPlayer {
+            sofifa_id,
+            rating_sum: f32::from_le_bytes(self.mmap[start + 4..start + 8].try_into().unwrap()),
+            num_ratings: read_u32(&self.mmap, start + 8),
+        })
+    }
+
+    // None when the index has no such player. Fields out of the strings
+    // section or not UTF-8 mean the file is corrupt, and are an error.
+    pub fn player(&self, sofifa_id: u32) -> Result<Option<PlayerRef<'_>>, String> {
+        let i = match self.position(sofifa_id) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let strings = &self.mmap[self.strings_start()..];
+
+        let field = |n: usize| -> Result<&str, String> {
+            let start = self.offset(i, n) as usize;
+            let end = self.offset(i, n + 1) as usize;
+            let bytes = match strings.get(start..end) {
+                Some(bytes) => bytes,
+                None => return Err(format!("corrupt index: player {} points past the strings", sofifa_id)),
+            };
+            std::str::from_utf8(bytes).map_err(|_| format!("corrupt index: player {} has text that is not UTF-8", sofifa_id))
+        };
+
+        Ok(Some(PlayerRef {
+            short_name: field(0)?,
+            long_name: field(1)?,
+            player_positions: field(2)?,
+            nationality: field(3)?,
+            club_name: field(4)?,
+            league_name: field(5)?,
+        }))
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_lookup() {
+        let mut players_table: HashMap<u32, Player> = HashMap::new(3);
+        let mut rating_table: HashMap<u32, RatingPlayer> = HashMap::new(3);
+        for (id, name) in [(20801, "Cristiano Ronaldo"), (158023, "L. Messi"), (41, "Iniesta")] {
+            players_table.insert(
+                id,
+                Player {
+                    sofifa_id: id,
+                    short_name: name.to_string(),
+                    long_name: format!("{} long", name),
+                    player_positions: "ST".to_string(),
+                    nationality: "N. Kanté".to_string(),
+                    club_name: String::new(),
+                    league_name: "League".to_string(),
+                    ..Default::default()
+                },
+            );
+            rating_table.insert(
+                id,
+                RatingPlayer {
+                    sofifa_id: id,
+                    rating_sum: 9.0,
+                    num_ratings: id,
+                },
+            );
+        }
+
+        let path = std::env::temp_dir().join(format!("tf_cpd_index_test_{}.idx", std::process::id()));
+        write_index(&path, &players_table, &rating_table).unwrap();
+
+        let index = MappedIndex::open(&path).unwrap();
+        assert_eq!(index.len(), 3);
+
+        let messi = index.player(158023).unwrap().unwrap();
+        assert_eq!(messi.short_name, "L. Messi");
+        assert_eq!(messi.long_name, "L. Messi long");
+        assert_eq!(messi.nationality, "N. Kanté");
+        assert_eq!(messi.club_name, "");
+        assert_eq!(messi.league_name, "League");
+        assert_eq!(index.rating(41).unwrap().num_ratings, 41);
+        assert!(index.player(7).unwrap().is_none());
+
+        // A name that is no longer UTF-8 is reported, not shown empty
+        let mut bytes = std::fs::read(&path).unwrap();
+        let at = bytes.windows(8).position(|w| w == b"L. Messi").unwrap();
+        bytes[at] = 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let index = MappedIndex::open(&path).unwrap();
+        assert!(index.player(158023).is_err());
+        assert!(index.player(41).unwrap().is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
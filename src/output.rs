@@ -0,0 +1,270 @@
+use prettytable::{Cell, Row, Table};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Markdown,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "markdown" | "md" => Ok(OutputFormat::Markdown),
+            _ => Err(format!(
+                "Invalid output format '{}', expected table, json, csv or markdown",
+                s
+            )),
+        }
+    }
+}
+
+// A single cell. Numbers keep the text they are shown with, JSON emits them
+// unquoted; Missing is "-" in tables, empty in CSV and null in JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(String),
+    Missing,
+}
+
+impl Value {
+    pub fn text<S: ToString>(value: S) -> Self {
+        Value::Text(value.to_string())
+    }
+
+    pub fn number<N: ToString>(value: N) -> Self {
+        Value::Number(value.to_string())
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Value::Text(text) | Value::Number(text) => text,
+            Value::Missing => "-",
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Text(text) => serde_json::Value::String(text.clone()),
+            Value::Number(text) => serde_json::from_str(text)
+                .unwrap_or_else(|_| serde_json::Value::String(text.clone())),
+            Value::Missing => serde_json::Value::Null,
+        }
+    }
+}
+
+// The result of a query: the same header and rows whatever the format
+pub struct Rows {
+    headers: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Rows {
+    pub fn new(headers: &[&str]) -> Self {
+        Rows {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    // Short rows are padded with Missing so every row has every column
+    pub fn push(&mut self, mut row: Vec<Value>) {
+        row.resize(self.headers.len(), Value::Missing);
+        self.rows.push(row);
+    }
+
+    pub fn print(&self, format: OutputFormat) {
+        print!("{}", self.render(format));
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Table => self.render_table(),
+            OutputFormat::Json => self.render_json(),
+            OutputFormat::Csv => self.render_csv(),
+            OutputFormat::Markdown => self.render_markdown(),
+        }
+    }
+
+    fn render_table(&self) -> String {
+        let mut table = Table::new();
+        table.add_row(Row::new(self.headers.iter().map(|h| Cell::new(h)).collect()));
+        for row in &self.rows {
+            table.add_row(Row::new(row.iter().map(|v| Cell::new(v.as_str())).collect()));
+        }
+        table.to_string()
+    }
+
+    // One array of objects per result, on a single line, keys in column order
+    fn render_json(&self) -> String {
+        format!("{}\n", self.to_json())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        self.rows
+            .iter()
+            .map(|row| {
+                serde_json::Value::Object(
+                    self.headers
+                        .iter()
+                        .cloned()
+                        .zip(row.iter().map(Value::to_json))
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn render_csv(&self) -> String {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        let _ = wtr.write_record(&self.headers);
+        for row in &self.rows {
+            let _ = wtr.write_record(row.iter().map(|v| match v {
+                Value::Missing => "",
+                v => v.as_str(),
+            }));
+        }
+        let bytes = wtr.into_inner().unwrap_or_default();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn render_markdown(&self) -> String {
+        let line = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+        let escape = |cell: &str| cell.replace('|', "\\|");
+
+        let mut out = line(self.headers.iter().map(|h| escape(h)).collect());
+        out += &line(self.headers.iter().map(|_| "---".to_string()).collect());
+        for row in &self.rows {
+            out += &line(row.iter().map(|v| escape(v.as_str())).collect());
+        }
+        out
+    }
+}
+
+// Several results answering one command, each under a key and a title.
+// JSON gets a single object with one array per key, tables and Markdown print
+// the sections one after the other. CSV has no way to hold tables with
+// different columns in one document, so it is refused.
+pub struct Sections {
+    sections: Vec<(&'static str, String, Rows)>,
+}
+
+impl Sections {
+    pub fn new() -> Self {
+        Sections { sections: Vec::new() }
+    }
+
+    // An empty section prints only its title outside JSON, e.g. "No tags"
+    pub fn push(&mut self, key: &'static str, title: impl Into<String>, rows: Rows) {
+        self.sections.push((key, title.into(), rows));
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<(), String> {
+        print!("{}", self.render(format)?);
+        Ok(())
+    }
+
+    pub fn render(&self, format: OutputFormat) -> Result<String, String> {
+        let mut out = String::new();
+        match format {
+            OutputFormat::Csv => {
+                return Err("This result has several tables and cannot be printed as csv, \
+                            use --format json, markdown or table"
+                    .to_string())
+            }
+            OutputFormat::Json => {
+                let object: serde_json::Map<String, serde_json::Value> = self
+                    .sections
+                    .iter()
+                    .map(|(key, _, rows)| (key.to_string(), rows.to_json()))
+                    .collect();
+                out = format!("{}\n", serde_json::Value::Object(object));
+            }
+            OutputFormat::Table | OutputFormat::Markdown => {
+                for (i, (_, title, rows)) in self.sections.iter().enumerate() {
+                    if i > 0 && format == OutputFormat::Markdown {
+                        out.push('\n');
+                    }
+                    if !title.is_empty() {
+                        out += &match format {
+                            OutputFormat::Markdown => format!("### {}\n\n", title),
+                            _ => format!("{}\n", title),
+                        };
+                    }
+                    if !rows.rows.is_empty() {
+                        out += &rows.render(format);
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Default for Sections {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Remarks around a result. They go to stderr in the machine-readable formats
+// so stdout stays parseable.
+pub fn note(format: OutputFormat, message: &str) {
+    match format {
+        OutputFormat::Table => println!("{}", message),
+        _ => eprintln!("{}", message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_formats() {
+        let mut rows = Rows::new(&["sofifa_id", "short_name", "rating"]);
+        rows.push(vec![Value::number(158023), Value::text("L. Messi"), Value::number("4.031021")]);
+        rows.push(vec![Value::number(7), Value::text("a|b, c")]);
+
+        assert_eq!(
+            rows.render(OutputFormat::Json),
+            "[{\"sofifa_id\":158023,\"short_name\":\"L. Messi\",\"rating\":4.031021},\
+             {\"sofifa_id\":7,\"short_name\":\"a|b, c\",\"rating\":null}]\n"
+        );
+        assert_eq!(
+            rows.render(OutputFormat::Csv),
+            "sofifa_id,short_name,rating\n158023,L. Messi,4.031021\n7,\"a|b, c\",\n"
+        );
+        assert_eq!(
+            rows.render(OutputFormat::Markdown),
+            "| sofifa_id | short_name | rating |\n| --- | --- | --- |\n\
+             | 158023 | L. Messi | 4.031021 |\n| 7 | a\\|b, c | - |\n"
+        );
+    }
+
+    #[test]
+    fn test_render_sections() {
+        let mut profile = Rows::new(&["field", "value"]);
+        profile.push(vec![Value::text("short_name"), Value::text("L. Messi")]);
+        let mut sections = Sections::new();
+        sections.push("profile", "", profile);
+        sections.push("tags", "No tags", Rows::new(&["tag", "count"]));
+
+        assert_eq!(
+            sections.render(OutputFormat::Json).unwrap(),
+            "{\"profile\":[{\"field\":\"short_name\",\"value\":\"L. Messi\"}],\"tags\":[]}\n"
+        );
+        assert_eq!(
+            sections.render(OutputFormat::Markdown).unwrap(),
+            "| field | value |\n| --- | --- |\n| short_name | L. Messi |\n\n### No tags\n\n"
+        );
+        assert!(sections.render(OutputFormat::Csv).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+
+use crate::hash_table::HashMap;
+use crate::input::ColumnMap;
+use crate::loader::{csv_error_message, LoadReport};
+use crate::structs::*;
+
+// Partial sums built by one thread from its chunk of rating.csv
+pub struct PartialRatings {
+    pub players: HashMap<u32, RatingPlayer>,
+    pub histograms: HashMap<u32, RatingHistogram>,
+    pub users: HashMap<u32, User>,
+}
+
+impl PartialRatings {
+    fn new() -> Self {
+        PartialRatings {
+            players: HashMap::new(3_000),
+            histograms: HashMap::new(3_000),
+            users: HashMap::new(20_000),
+        }
+    }
+
+    fn add(&mut self, record: RatingFile) {
+        let user_rating = RatingPlayer {
+            sofifa_id: record.sofifa_id,
+            rating_sum: record.rating,
+            num_ratings: 1,
+        };
+
+        match self.users.search(&record.user_id) {
+            Some(user) => user.add_rating(user_rating),
+            None => self.users.insert(
+                record.user_id,
+                User {
+                    user_id: record.user_id,
+                    ratings: vec![user_rating],
+                },
+            ),
+        }
+
+        match self.histograms.search(&record.sofifa_id) {
+            Some(histogram) => histogram.add(record.rating),
+            None => {
+                let mut histogram = RatingHistogram::new(record.sofifa_id);
+                histogram.add(record.rating);
+                self.histograms.insert(record.sofifa_id, histogram);
+            }
+        }
+
+        match self.players.search(&record.sofifa_id) {
+            Some(player) => player.add_rating(record.rating),
+            None => self.players.insert(
+                record.sofifa_id,
+                RatingPlayer {
+                    sofifa_id: record.sofifa_id,
+                    rating_sum: record.rating,
+                    num_ratings: 1,
+                },
+            ),
+        }
+    }
+}
+
+// What one thread read from its byte range. Error lines are relative to the
+// range, the lines of the ranges before it are only known once all are read.
+struct Chunk {
+    partial: PartialRatings,
+    rows_read: usize,
+    errors: Vec<(Option<u64>, String)>,
+    lines: u64,
+    fatal: Option<String>,
+}
+
+// Counts the lines going through a reader
+struct LineCounter<R> {
+    inner: R,
+    lines: u64,
+}
+
+impl<R: Read> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.lines += buf[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(n)
+    }
+}
+
+// Splits the file body into `threads` byte ranges on line boundaries and
+// parses each range on its own thread, which reads it from the file through
+// its own buffer. Partials are returned in file order, so merging them in
+// sequence keeps every user's ratings in file order.
+pub fn aggregate_ratings_parallel<P: AsRef<Path>>(
+    filename: P,
+    threads: usize,
+    delimiter: u8,
+    columns: &ColumnMap,
+    strict: bool,
+) -> (Vec<PartialRatings>, LoadReport) {
+    let mut report = LoadReport::new(&filename);
+    let filename = filename.as_ref();
+
+    let mut header = Vec::new();
+    let len = File::open(filename).and_then(|file| {
+        let len = file.metadata()?.len();
+        BufReader::new(file).read_until(b'\n', &mut header)?;
+        Ok(len)
+    });
+    let len = match len {
+        Ok(len) => len,
+        Err(e) => {
+            report.fatal = Some(e.to_string());
+            return (Vec::new(), report);
+        }
+    };
+    if !header.ends_with(b"\n") {
+        return (Vec::new(), report);
+    }
+
+    let headers = match csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(header.as_slice())
+        .headers()
+    {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            report.fatal = Some(e.to_string());
+            return (Vec::new(), report);
+        }
+    };
+    let headers = match columns.map_headers::<RatingFile>(&headers) {
+        Ok(headers) => headers,
+        Err(e) => {
+            report.fatal = Some(e);
+            return (Vec::new(), report);
+        }
+    };
+
+    let ranges = match split_ranges(filename, header.len() as u64, len, threads.max(1)) {
+        Ok(ranges) => ranges,
+        Err(e) => {
+            report.fatal = Some(e.to_string());
+            return (Vec::new(), report);
+        }
+    };
+
+    let chunks: Vec<Chunk> = thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                let headers = &headers;
+                scope.spawn(move || read_range(filename, start, end, headers, delimiter, strict))
+            })
+            .collect();
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    let mut partials = Vec::with_capacity(chunks.len());
+    // The header is line 1
+    let mut lines_before: u64 = 1;
+    for chunk in chunks {
+        report.rows_read += chunk.rows_read;
+        for (line, error) in chunk.errors {
+            report.add_error(line.map(|line| line + lines_before), error);
+        }
+        if report.fatal.is_none() {
+            report.fatal = chunk.fatal;
+        }
+        lines_before += chunk.lines;
+        partials.push(chunk.partial);
+    }
+    (partials, report)
+}
+
+fn read_range(
+    filename: &Path,
+    start: u64,
+    end: u64,
+    headers: &csv::StringRecord,
+    delimiter: u8,
+    strict: bool,
+) -> Chunk {
+    let mut chunk = Chunk {
+        partial: PartialRatings::new(),
+        rows_read: 0,
+        errors: Vec::new(),
+        lines: 0,
+        fatal: None,
+    };
+
+    let mut file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) => {
+            chunk.fatal = Some(e.to_string());
+            return chunk;
+        }
+    };
+    if let Err(e) = file.seek(SeekFrom::Start(start)) {
+        chunk.fatal = Some(e.to_string());
+        return chunk;
+    }
+
+    let counter = LineCounter {
+        inner: BufReader::new(file).take(end - start),
+        lines: 0,
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .from_reader(counter);
+
+    for result in rdr.records() {
+        chunk.rows_read += 1;
+        match result.and_then(|record| record.deserialize(Some(headers))) {
+            Ok(record) => chunk.partial.add(record),
+            Err(e) => {
+                chunk.errors.push((e.position().map(|p| p.line()), csv_error_message(&e)));
+                if strict {
+                    break;
+                }
+            }
+        }
+    }
+
+    // The rest of the range still counts for the line numbers that follow
+    let counter = rdr.get_mut();
+    if let Err(e) = io::copy(counter, &mut io::sink()) {
+        chunk.fatal = Some(e.to_string());
+    }
+    chunk.lines = counter.lines;
+    chunk
+}
+
+// Byte ranges of about the same size covering `start..len`, each ending on a
+// line boundary
+fn split_ranges(filename: &Path, start: u64, len: u64, parts: usize) -> io::Result<Vec<(u64, u64)>> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let target = (len - start) / parts as u64 + 1;
+    let mut ranges = Vec::with_capacity(parts);
+    let mut line = Vec::new();
+    let mut begin = start;
+
+    while begin < len {
+        let mut end = std::cmp::min(begin + target, len);
+        if end < len {
+            // Move on to the end of the line `end` falls in
+            reader.seek(SeekFrom::Start(end - 1))?;
+            line.clear();
+            end = end - 1 + reader.read_until(b'\n', &mut line)? as u64;
+        }
+        ranges.push((begin, end));
+        begin = end;
+    }
+
+    Ok(ranges)
+}
+
+// Adds a thread's partial sums into the global tables
+pub fn merge_partial_ratings(
+    partial: PartialRatings,
+    rating_table: &mut HashMap<u32, RatingPlayer>,
+    histograms: &mut HashMap<u32, RatingHistogram>,
+    user_table: &mut HashMap<u32, User>,
+    quarantine: &mut Quarantine,
+) {
+    for (sofifa_id, player) in partial.players.into_entries() {
+        match rating_table.search(&sofifa_id) {
+            Some(rating) => {
+                rating.rating_sum += player.rating_sum;
+                rating.num_ratings += player.num_ratings;
+            }
+            None => quarantine.add_ratings(player),
+        }
+    }
+
+    for (sofifa_id, partial) in partial.histograms.into_entries() {
+        if let Some(histogram) = histograms.search(&sofifa_id) {
+            histogram.merge(&partial);
+        }
+    }
+
+    for (user_id, mut user) in partial.users.into_entries() {
+        user.ratings
+            .retain(|rating| rating_table.search_non_mut(&rating.sofifa_id).is_some());
+        if user.ratings.is_empty() {
+            continue;
+        }
+
+        match user_table.search(&user_id) {
+            Some(existing) => existing.ratings.extend(user.ratings),
+            None => user_table.insert(user_id, user),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Tables;
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let path = std::env::temp_dir().join(format!("tf_cpd_parallel_test_{}.csv", std::process::id()));
+        let mut csv = "user_id,sofifa_id,rating\n".to_string();
+        for i in 0..40u32 {
+            csv.push_str(&format!("{},{},{}\n", i % 7, 10 + i % 3, 0.5 + (i % 10) as f32 * 0.5));
+        }
+        // A bad row on line 32 and a player missing from the players file
+        csv.insert_str(csv.match_indices('\n').nth(30).unwrap().0 + 1, "x,10,1.0\n");
+        csv.push_str("3,99,4.0\n");
+        std::fs::write(&path, &csv).unwrap();
+
+        let with_players = || {
+            let mut tables = Tables::new();
+            for sofifa_id in [10, 11, 12] {
+                tables.add_player(Player {
+                    sofifa_id,
+                    ..Default::default()
+                });
+            }
+            tables
+        };
+
+        let mut expected = with_players();
+        let mut quarantine = Quarantine::new(false);
+        for line in csv.lines().skip(1) {
+            let fields: Vec<&str> = line.split(',').collect();
+            if let (Ok(user_id), Ok(sofifa_id), Ok(rating)) = (fields[0].parse(), fields[1].parse(), fields[2].parse()) {
+                let record = RatingFile {
+                    user_id,
+                    sofifa_id,
+                    rating,
+                };
+                expected.add_rating(record, DedupPolicy::CountAll, &mut quarantine);
+            }
+        }
+
+        for threads in [1, 3, 8] {
+            let (partials, report) =
+                aggregate_ratings_parallel(&path, threads, b',', &ColumnMap::default(), false);
+            assert_eq!(report.rows_read, 42);
+            assert_eq!(report.rows_skipped, 1);
+            assert!(report.errors[0].starts_with("line 32:"), "{:?}", report.errors);
+
+            let mut tables = with_players();
+            let mut parallel_quarantine = Quarantine::new(false);
+            for partial in partials {
+                merge_partial_ratings(
+                    partial,
+                    &mut tables.rating_table,
+                    &mut tables.histograms,
+                    &mut tables.user_table,
+                    &mut parallel_quarantine,
+                );
+            }
+
+            assert_eq!(parallel_quarantine.orphan_ratings, quarantine.orphan_ratings);
+            for sofifa_id in [10, 11, 12] {
+                let expected_rating = expected.rating_table.search_non_mut(&sofifa_id).unwrap();
+                let rating = tables.rating_table.search_non_mut(&sofifa_id).unwrap();
+                assert_eq!(rating.num_ratings, expected_rating.num_ratings);
+                assert_eq!(rating.rating_sum, expected_rating.rating_sum);
+            }
+            for user_id in 0..7 {
+                let ids = |tables: &Tables| -> Vec<u32> {
+                    let user = tables.user_table.search_non_mut(&user_id).unwrap();
+                    user.ratings.iter().map(|r| r.sofifa_id).collect()
+                };
+                assert_eq!(ids(&tables), ids(&expected));
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+// The position codes of the sofifa players file
+pub const POSITIONS: &[&str] = &[
+    "GK", "CB", "LB", "RB", "LWB", "RWB", "CDM", "CM", "CAM", "LM", "RM", "LW", "RW", "CF", "ST",
+];
+
+// Names that stand for several positions
+pub const GROUPS: &[(&str, &[&str])] = &[
+    ("DEF", &["CB", "LB", "RB", "LWB", "RWB"]),
+    ("MID", &["CDM", "CM", "CAM", "LM", "RM"]),
+    ("ATT", &["ST", "CF", "LW", "RW"]),
+];
+
+// " st" -> Some("ST"), unknown codes -> None
+pub fn normalize(code: &str) -> Option<&'static str> {
+    let code = code.trim().to_uppercase();
+    POSITIONS.iter().copied().find(|known| *known == code)
+}
+
+// The codes a position or group name stands for
+pub fn expand(name: &str) -> Result<Vec<&'static str>, String> {
+    if let Some(code) = normalize(name) {
+        return Ok(vec![code]);
+    }
+    let upper = name.trim().to_uppercase();
+    match GROUPS.iter().find(|(group, _)| *group == upper) {
+        Some((_, codes)) => Ok(codes.to_vec()),
+        None => {
+            let groups: Vec<&str> = GROUPS.iter().map(|(group, _)| *group).collect();
+            Err(format!(
+                "Unknown position '{}', expected one of {} or a group {}",
+                name,
+                POSITIONS.join(", "),
+                groups.join(", ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_positions() {
+        assert_eq!(normalize(" st "), Some("ST"));
+        assert_eq!(normalize("XX"), None);
+        assert_eq!(expand("cf").unwrap(), vec!["CF"]);
+        assert_eq!(expand("def").unwrap(), vec!["CB", "LB", "RB", "LWB", "RWB"]);
+        assert_eq!(expand("ATT").unwrap(), vec!["ST", "CF", "LW", "RW"]);
+        assert!(expand("Striker").unwrap_err().starts_with("Unknown position 'Striker'"));
+    }
+}
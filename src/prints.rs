@@ -1,189 +1,462 @@
-use prettytable::{Cell, Row, Table};
+use crate::output::*;
 use crate::structs::*;
 use crate::hash_table::*;
+use crate::loader::LoadReport;
+use crate::mmap_index::{MappedIndex, PlayerRef};
+use crate::query::GroupStats;
 
-pub fn print_player_and_rating(
-    ratings: &Vec<RatingPlayer>,
-    players_table: &HashMap<u32, Player>,
-    rating_table: &HashMap<u32, RatingPlayer>,
-) {
-    let mut table: Table = Table::new();
+const UNKNOWN_PLAYER: &str = "unknown player";
 
-    table.add_row(Row::new(vec![
-        Cell::new("sofifa_id"),
-        Cell::new("short_name"),
-        Cell::new("long_name"),
-        Cell::new("global_rating"),
-        Cell::new("rating"),
-        Cell::new("count"),
-    ]));
+// A raw cell before formatting
+pub enum Raw<'a> {
+    Text(&'a str),
+    Int(u64),
+    Float(f32),
+    Missing,
+}
+
+// One column of a view: its header, how to read it from a row and how to
+// show what was read
+pub struct Column<R> {
+    pub name: &'static str,
+    pub extract: for<'r> fn(&'r R) -> Raw<'r>,
+    pub format: fn(Raw) -> Value,
+}
 
-    for player in ratings {
-        let player_infos: &Player = players_table.search_non_mut(&player.sofifa_id).unwrap();
+pub fn plain(raw: Raw) -> Value {
+    match raw {
+        Raw::Text(text) => Value::text(text),
+        Raw::Int(n) => Value::number(n),
+        Raw::Float(x) => Value::number(x),
+        Raw::Missing => Value::Missing,
+    }
+}
+
+// Six decimals, as the rating tables have always shown them: 4.031021, 3.500000
+pub fn rounded(raw: Raw) -> Value {
+    match raw {
+        Raw::Float(x) => Value::number(format!("{:.6}", x)),
+        raw => plain(raw),
+    }
+}
 
-        let rating = player.rating_sum / player.num_ratings as f32;
+// Builds the rows of a view from its columns
+pub fn render<R>(items: &[R], columns: &[&Column<R>]) -> Rows {
+    let names: Vec<&str> = columns.iter().map(|c| c.name).collect();
+    let mut rows = Rows::new(&names);
+    for item in items {
+        rows.push(columns.iter().map(|c| (c.format)((c.extract)(item))).collect());
+    }
+    rows
+}
 
-        let global_rating_info: &RatingPlayer =
-            rating_table.search_non_mut(&player.sofifa_id).unwrap();
+// Looks up columns by name, naming every unknown one in the error
+pub fn select_columns<'c, R, S: AsRef<str>>(
+    available: &'c [Column<R>],
+    names: &[S],
+) -> Result<Vec<&'c Column<R>>, String> {
+    let mut selected = Vec::new();
+    let mut unknown = Vec::new();
+    for name in names {
+        match available.iter().find(|c| c.name == name.as_ref()) {
+            Some(column) => selected.push(column),
+            None => unknown.push(name.as_ref()),
+        }
+    }
 
-        let global_rating: f32 =
-            global_rating_info.rating_sum / global_rating_info.num_ratings as f32;
+    if unknown.is_empty() {
+        Ok(selected)
+    } else {
+        let known: Vec<&str> = available.iter().map(|c| c.name).collect();
+        Err(format!(
+            "Unknown column(s) {}, expected some of {}",
+            unknown.join(", "),
+            known.join(", ")
+        ))
+    }
+}
 
-        table.add_row(Row::new(vec![
-            Cell::new(&player_infos.sofifa_id.to_string()),
-            Cell::new(&player_infos.short_name),
-            Cell::new(&player_infos.long_name),
-            Cell::new(&global_rating.to_string()),
-            Cell::new(&rating.to_string()),
-            Cell::new(&global_rating_info.num_ratings.to_string()),
-        ]));
+// "sofifa_id, short_name,rating" -> ["sofifa_id", "short_name", "rating"]
+pub fn parse_columns(list: &str) -> Result<Vec<String>, String> {
+    let columns: Vec<String> = list
+        .split(',')
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect();
+    if columns.is_empty() {
+        return Err("No columns given".to_string());
     }
-    table.printstd();
+    Ok(columns)
 }
 
-pub fn print_player_and_rating_with_lim(
-    ratings: &Vec<RatingPlayer>,
-    players_table: &HashMap<u32, Player>,
-    rating_table: &HashMap<u32, RatingPlayer>,
-    lim: usize,
-) {
-    let slice_len = std::cmp::min(lim, ratings.len());
-    let slice: Vec<RatingPlayer> = ratings[..slice_len].to_vec();
+// A rated player as shown by the player, user, tags and top views. `rating`
+// is what the row is about (one user's rating or the global one), `global`
+// the player's global rating.
+pub struct PlayerRow<'a> {
+    pub rating: &'a RatingPlayer,
+    pub player: Option<PlayerSource<'a>>,
+    pub global: Option<&'a RatingPlayer>,
+}
+
+// Where a row's player comes from: the players table has every field, an
+// index only the text ones
+pub enum PlayerSource<'a> {
+    Table(&'a Player),
+    Index(PlayerRef<'a>),
+}
+
+impl<'a> PlayerRow<'a> {
+    pub fn new(
+        rating: &'a RatingPlayer,
+        players_table: &'a HashMap<u32, Player>,
+        rating_table: &'a HashMap<u32, RatingPlayer>,
+    ) -> Self {
+        PlayerRow {
+            rating,
+            player: players_table.search_non_mut(&rating.sofifa_id).map(PlayerSource::Table),
+            global: rating_table.search_non_mut(&rating.sofifa_id),
+        }
+    }
+
+    // Text columns of a player missing from players.csv all read "unknown player"
+    fn text(&self, field: for<'p> fn(&PlayerRef<'p>) -> &'p str) -> Raw<'_> {
+        match &self.player {
+            Some(PlayerSource::Table(player)) => Raw::Text(field(&PlayerRef::from(*player))),
+            Some(PlayerSource::Index(player)) => Raw::Text(field(player)),
+            None => Raw::Text(UNKNOWN_PLAYER),
+        }
+    }
 
-    let mut table: Table = Table::new();
+    // The player's other fields, which only the players table has
+    fn loaded(&self) -> Option<&Player> {
+        match self.player {
+            Some(PlayerSource::Table(player)) => Some(player),
+            _ => None,
+        }
+    }
 
-    table.add_row(Row::new(vec![
-        Cell::new("sofifa_id"),
-        Cell::new("short_name"),
-        Cell::new("long_name"),
-        Cell::new("global_rating"),
-        Cell::new("rating"),
-        Cell::new("count"),
-    ]));
+    fn number(&self, field: fn(&Player) -> Option<u32>) -> Raw<'_> {
+        match self.loaded().and_then(field) {
+            Some(n) => Raw::Int(n as u64),
+            None => Raw::Missing,
+        }
+    }
+}
+
+// Every column a player view can show
+pub fn player_columns<'a>() -> Vec<Column<PlayerRow<'a>>> {
+    vec![
+        Column { name: "sofifa_id", extract: |r| Raw::Int(r.rating.sofifa_id as u64), format: plain },
+        Column { name: "short_name", extract: |r| r.text(|p| p.short_name), format: plain },
+        Column { name: "long_name", extract: |r| r.text(|p| p.long_name), format: plain },
+        Column { name: "player_positions", extract: |r| r.text(|p| p.player_positions), format: plain },
+        Column { name: "nationality", extract: |r| r.text(|p| p.nationality), format: plain },
+        Column { name: "club_name", extract: |r| r.text(|p| p.club_name), format: plain },
+        Column { name: "league_name", extract: |r| r.text(|p| p.league_name), format: plain },
+        Column { name: "age", extract: |r| r.number(|p| p.age), format: plain },
+        Column { name: "height_cm", extract: |r| r.number(|p| p.height_cm), format: plain },
+        Column { name: "weight_kg", extract: |r| r.number(|p| p.weight_kg), format: plain },
+        Column { name: "overall", extract: |r| r.number(|p| p.overall), format: plain },
+        Column { name: "potential", extract: |r| r.number(|p| p.potential), format: plain },
+        Column { name: "value_eur", extract: |r| r.number(|p| p.value_eur), format: plain },
+        Column { name: "wage_eur", extract: |r| r.number(|p| p.wage_eur), format: plain },
+        Column {
+            name: "preferred_foot",
+            extract: |r| match r.loaded().and_then(|p| p.preferred_foot.as_deref()) {
+                Some(foot) => Raw::Text(foot),
+                None => Raw::Missing,
+            },
+            format: plain,
+        },
+        Column { name: "weak_foot", extract: |r| r.number(|p| p.weak_foot), format: plain },
+        Column { name: "skill_moves", extract: |r| r.number(|p| p.skill_moves), format: plain },
+        Column { name: "pace", extract: |r| r.number(|p| p.pace), format: plain },
+        Column { name: "shooting", extract: |r| r.number(|p| p.shooting), format: plain },
+        Column { name: "passing", extract: |r| r.number(|p| p.passing), format: plain },
+        Column { name: "dribbling", extract: |r| r.number(|p| p.dribbling), format: plain },
+        Column { name: "defending", extract: |r| r.number(|p| p.defending), format: plain },
+        Column { name: "physic", extract: |r| r.number(|p| p.physic), format: plain },
+        Column { name: "rating", extract: |r| Raw::Float(r.rating.average()), format: rounded },
+        Column { name: "count", extract: |r| Raw::Int(r.rating.num_ratings as u64), format: plain },
+        Column {
+            name: "global_rating",
+            extract: |r| match r.global {
+                Some(global) => Raw::Float(global.average()),
+                None => Raw::Missing,
+            },
+            format: rounded,
+        },
+        Column {
+            name: "global_count",
+            extract: |r| Raw::Int(r.global.map(|g| g.num_ratings).unwrap_or(0) as u64),
+            format: plain,
+        },
+    ]
+}
+
+pub fn player_rows<S: AsRef<str>>(rows: &[PlayerRow], columns: &[S]) -> Result<Rows, String> {
+    let available = player_columns();
+    let columns = select_columns(&available, columns)?;
+    Ok(render(rows, &columns))
+}
 
-    for player in slice {
-        let player_infos: &Player = players_table.search_non_mut(&player.sofifa_id).unwrap();
+pub fn print_player_rows<S: AsRef<str>>(
+    rows: &[PlayerRow],
+    columns: &[S],
+    format: OutputFormat,
+) -> Result<(), String> {
+    player_rows(rows, columns)?.print(format);
+    Ok(())
+}
 
-        let rating = player.rating_sum / player.num_ratings as f32;
+pub fn print_indexed_players<S: AsRef<str>>(
+    index: &MappedIndex,
+    ids: &[u32],
+    columns: &[S],
+    format: OutputFormat,
+) -> Result<(), String> {
+    // Players found are still printed, the missing ones make the error
+    let mut entries = Vec::new();
+    let mut missing = Vec::new();
+    for id in ids {
+        match (index.player(*id)?, index.rating(*id)) {
+            (Some(player), Some(rating)) => entries.push((player, rating)),
+            _ => missing.push(id.to_string()),
+        }
+    }
 
-        let global_rating_info: &RatingPlayer =
-            rating_table.search_non_mut(&player.sofifa_id).unwrap();
+    let rows: Vec<PlayerRow> = entries
+        .iter()
+        .map(|(player, rating)| PlayerRow {
+            rating,
+            player: Some(PlayerSource::Index(*player)),
+            global: Some(rating),
+        })
+        .collect();
 
-        let global_rating: f32 =
-            global_rating_info.rating_sum / global_rating_info.num_ratings as f32;
+    note(format, &format!("{} players in index", index.len()));
+    print_player_rows(&rows, columns, format)?;
+    if !missing.is_empty() {
+        return Err(format!("No player {} in the index", missing.join(", ")));
+    }
+    Ok(())
+}
 
-        table.add_row(Row::new(vec![
-            Cell::new(&player_infos.sofifa_id.to_string()),
-            Cell::new(&player_infos.short_name),
-            Cell::new(&player_infos.long_name),
-            Cell::new(&global_rating.to_string()),
-            Cell::new(&rating.to_string()),
-            Cell::new(&global_rating_info.num_ratings.to_string()),
-        ]));
+// Every player column, one per line. The row is about the global rating, so
+// the global_* columns would only repeat it.
+pub fn profile_rows(row: &PlayerRow) -> Rows {
+    let mut rows = Rows::new(&["field", "value"]);
+    for column in player_columns() {
+        if column.name.starts_with("global_") {
+            continue;
+        }
+        rows.push(vec![Value::text(column.name), (column.format)((column.extract)(row))]);
     }
-    table.printstd();
+    rows
 }
 
-pub fn print_player_and_rating_long(
-    players_position_ratings: &Vec<&RatingPlayer>,
-    top_num: u32,
-    players_table: &HashMap<u32, Player>,
-) {
-    let mut table = Table::new();
-
-    table.add_row(Row::new(vec![
-        Cell::new("sofifa_id"),
-        Cell::new("short_name"),
-        Cell::new("long_name"),
-        Cell::new("Player_position"),
-        Cell::new("nationality"),
-        Cell::new("club_name"),
-        Cell::new("league_name"),
-        Cell::new("rating"),
-        Cell::new("count"),
-    ]));
-
-    for final_players in 0..top_num {
-        let ratings: &RatingPlayer = &players_position_ratings[final_players as usize];
-
-        let temp_player: Option<&Player> = players_table.search_non_mut(&ratings.sofifa_id);
-
-        let rating_global = ratings.rating_sum / ratings.num_ratings as f32;
-
-        match temp_player {
-            Some(player_infos) => {
-                table.add_row(Row::new(vec![
-                    Cell::new(&player_infos.sofifa_id.to_string()),
-                    Cell::new(&player_infos.short_name),
-                    Cell::new(&player_infos.long_name),
-                    Cell::new(&player_infos.player_positions),
-                    Cell::new(&player_infos.nationality),
-                    Cell::new(&player_infos.club_name),
-                    Cell::new(&player_infos.league_name),
-                    Cell::new(&format!("{:.6}", rating_global).to_string()),
-                    Cell::new(&ratings.num_ratings.to_string()),
-                ]));
-            }
-            None => {}
+// Width of the longest bar of a histogram, in characters
+const HISTOGRAM_WIDTH: usize = 40;
+
+// Count and share of each rating; tables also get a bar per rating
+pub fn histogram_rows(histogram: &RatingHistogram, format: OutputFormat) -> Rows {
+    let total: u32 = histogram.buckets.iter().sum();
+    let longest = histogram.buckets.iter().copied().max().unwrap_or(0);
+
+    let mut headers = vec!["rating", "count", "share"];
+    if format == OutputFormat::Table {
+        headers.push("bar");
+    }
+    let mut rows = Rows::new(&headers);
+    for (bucket, count) in histogram.buckets.iter().enumerate() {
+        let share = match total {
+            0 => 0.0,
+            total => *count as f32 * 100.0 / total as f32,
+        };
+        let bar = match longest {
+            0 => 0,
+            longest => (*count as usize * HISTOGRAM_WIDTH).div_ceil(longest as usize),
+        };
+
+        let mut row = vec![
+            Value::number(format!("{:.1}", RatingHistogram::bucket_rating(bucket))),
+            Value::number(count),
+            Value::number(format!("{:.1}", share)),
+        ];
+        if format == OutputFormat::Table {
+            row.push(Value::text("#".repeat(bar)));
         }
+        rows.push(row);
     }
-    table.printstd();
+    rows
 }
 
-pub fn print_player_and_rating_with_positon(
-    ratings: &Vec<RatingPlayer>,
-    players_table: &HashMap<u32, Player>,
-) {
-    let mut table: Table = Table::new();
+pub fn tag_rows(tags: &[(&str, u32)]) -> Rows {
+    let mut rows = Rows::new(&["tag", "count"]);
+    for (tag, count) in tags {
+        rows.push(vec![Value::text(tag), Value::number(count)]);
+    }
+    rows
+}
 
-    table.add_row(Row::new(vec![
-        Cell::new("sofifa_id"),
-        Cell::new("short_name"),
-        Cell::new("long_name"),
-        Cell::new("player_position"),
-        Cell::new("rating"),
-        Cell::new("count"),
-    ]));
+const GROUP_STATS_COLUMNS: &[Column<GroupStats>] = &[
+    Column { name: "players", extract: |g| Raw::Int(g.players as u64), format: plain },
+    Column { name: "rated_players", extract: |g| Raw::Int(g.rated_players as u64), format: plain },
+    Column { name: "ratings", extract: |g| Raw::Int(g.ratings), format: plain },
+    Column {
+        name: "rating",
+        extract: |g| match g.rating {
+            Some(rating) => Raw::Float(rating),
+            None => Raw::Missing,
+        },
+        format: rounded,
+    },
+    Column {
+        name: "best_player",
+        extract: |g| match &g.best_player {
+            Some(name) => Raw::Text(name),
+            None => Raw::Missing,
+        },
+        format: plain,
+    },
+];
 
-    for player in ratings {
-        let player_infos: &Player = players_table.search_non_mut(&player.sofifa_id).unwrap();
+// The first column is named after what the groups are: club, league, ...
+pub fn print_group_stats(groups: &[GroupStats], by: &'static str, format: OutputFormat) {
+    let group = Column { name: by, extract: |g: &GroupStats| Raw::Text(&g.group), format: plain };
+    let mut columns = vec![&group];
+    columns.extend(GROUP_STATS_COLUMNS.iter());
+    render(groups, &columns).print(format);
+}
+
+const LOAD_REPORT_COLUMNS: &[Column<LoadReport>] = &[
+    Column { name: "file", extract: |r| Raw::Text(&r.file), format: plain },
+    Column { name: "rows_read", extract: |r| Raw::Int(r.rows_read as u64), format: plain },
+    Column { name: "rows_skipped", extract: |r| Raw::Int(r.rows_skipped as u64), format: plain },
+    Column {
+        name: "duplicates",
+        extract: |r| match r.duplicates {
+            Some(duplicates) => Raw::Int(duplicates as u64),
+            None => Raw::Missing,
+        },
+        format: plain,
+    },
+];
+
+// Load reports describe the run rather than answer a command, so only an
+// interactive table session shows them on stdout. Batch runs and the other
+// formats keep stdout for command results.
+fn report(format: OutputFormat, batch: bool, text: &str) {
+    if format == OutputFormat::Table && !batch {
+        print!("{}", text);
+    } else {
+        eprint!("{}", text);
+    }
+}
 
-        let rating = player.rating_sum / player.num_ratings as f32;
+pub fn print_load_report(reports: &[LoadReport], format: OutputFormat, batch: bool) {
+    let columns: Vec<&Column<LoadReport>> = LOAD_REPORT_COLUMNS.iter().collect();
+    let mut text = render(reports, &columns).render(format);
 
-        table.add_row(Row::new(vec![
-            Cell::new(&player_infos.sofifa_id.to_string()),
-            Cell::new(&player_infos.short_name),
-            Cell::new(&player_infos.long_name),
-            Cell::new(&player_infos.player_positions),
-            Cell::new(&format!("{:.6}", rating).to_string()),
-            Cell::new(&player.num_ratings.to_string()),
-        ]));
+    for report in reports {
+        if let Some(fatal) = &report.fatal {
+            text += &format!("{}: {}\n", report.file, fatal);
+        }
+        for error in &report.errors {
+            text += &format!("{}: {}\n", report.file, error);
+        }
+        if report.unknown_positions > 0 {
+            text += &format!("{}: {} unknown position codes ignored\n", report.file, report.unknown_positions);
+        }
+        if report.invalid_numbers > 0 {
+            text += &format!("{}: {} cells are not numbers and were left empty\n", report.file, report.invalid_numbers);
+        }
+        if report.rows_skipped > report.errors.len() {
+            text += &format!(
+                "{}: ... and {} more\n",
+                report.file,
+                report.rows_skipped - report.errors.len()
+            );
+        }
     }
-    table.printstd();
+    report(format, batch, &text);
 }
 
+pub fn print_quarantine_summary(quarantine: &Quarantine, format: OutputFormat, batch: bool) {
+    if quarantine.orphan_ratings == 0 && quarantine.orphan_tags == 0 {
+        return;
+    }
+
+    let mut text = format!(
+        "{} ratings and {} tags reference players missing from the players file\n",
+        quarantine.orphan_ratings, quarantine.orphan_tags
+    );
+    if quarantine.keep {
+        text += &format!(
+            "quarantined ratings for {} unknown players and {} tags\n",
+            quarantine.unknown_players,
+            quarantine.tags.len()
+        );
+    }
+    report(format, batch, &text);
+}
 
 pub fn print_table_statistics(
     duration: std::time::Duration,
+    threads: usize,
     players_table: &HashMap<u32, Player>,
     rating_table: &HashMap<u32, RatingPlayer>,
     user_table: &HashMap<u32, User>,
+    format: OutputFormat,
 )
 {
-    println!("Tempo gasto: {:?}", duration);
-    println!("player table {:?}", players_table.occupancy());
-    println!("rating table {:?}", rating_table.occupancy());
-    println!("user table {:?}", user_table.occupancy());
-    println!(
-        "player table avg {:?}",
-        players_table.average_bucket_length()
+    note(format, &format!("Tempo gasto: {:?}", duration));
+    if threads > 1 {
+        note(format, &format!("loaded with {} threads", threads));
+    } else {
+        note(format, "loaded single-threaded");
+    }
+    note(format, &format!("player table {:?}", players_table.occupancy()));
+    note(format, &format!("rating table {:?}", rating_table.occupancy()));
+    note(format, &format!("user table {:?}", user_table.occupancy()));
+    note(
+        format,
+        &format!("player table avg {:?}", players_table.average_bucket_length()),
     );
-    println!(
-        "rating table avg {:?}",
-        rating_table.average_bucket_length()
+    note(
+        format,
+        &format!("rating table avg {:?}", rating_table.average_bucket_length()),
     );
-    println!(
-        "user table avg {:?}",
-        user_table.average_bucket_length()
+    note(
+        format,
+        &format!("user table avg {:?}", user_table.average_bucket_length()),
     );
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_selected_columns() {
+        let rating = RatingPlayer {
+            sofifa_id: 7,
+            rating_sum: 10.5,
+            num_ratings: 3,
+        };
+        let row = PlayerRow {
+            rating: &rating,
+            player: None,
+            global: None,
+        };
+
+        let available = player_columns();
+        let columns = select_columns(&available, &["sofifa_id", "short_name", "rating", "global_rating"]).unwrap();
+        assert_eq!(
+            render(&[row], &columns).render(OutputFormat::Csv),
+            "sofifa_id,short_name,rating,global_rating\n7,unknown player,3.500000,\n"
+        );
+
+        let error = select_columns(&available, &["rating", "shoe_size"]).err().unwrap();
+        assert!(error.starts_with("Unknown column(s) shoe_size"));
+    }
+}
@@ -0,0 +1,535 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::loader::Tables;
+use crate::positions;
+use crate::prints::*;
+use crate::sort::sort_players_by_rating;
+use crate::structs::*;
+use crate::trie::Trie;
+
+const DEFAULT_LIMIT: usize = 20;
+const DEFAULT_TOP: usize = 10;
+
+// Fields with a secondary index, by clause name and its aliases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Position,
+    Nationality,
+    Club,
+    League,
+}
+
+impl Field {
+    pub fn parse(key: &str) -> Option<Field> {
+        match key {
+            "position" => Some(Field::Position),
+            "nationality" | "country" => Some(Field::Nationality),
+            "club" | "club_name" => Some(Field::Club),
+            "league" | "league_name" => Some(Field::League),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Position => "position",
+            Field::Nationality => "nationality",
+            Field::Club => "club",
+            Field::League => "league",
+        }
+    }
+
+    // What a player holds for this field; a player has several positions
+    fn values(self, player: &Player) -> Vec<String> {
+        match self {
+            Field::Position => player.positions().iter().map(|code| code.to_string()).collect(),
+            Field::Nationality => vec![player.nationality.clone()],
+            Field::Club => vec![player.club_name.clone()],
+            Field::League => vec![player.league_name.clone()],
+        }
+    }
+
+    pub fn index(self, tables: &Tables) -> &Trie {
+        match self {
+            Field::Position => &tables.position_player,
+            Field::Nationality => &tables.nationality_player,
+            Field::Club => &tables.club_player,
+            Field::League => &tables.league_player,
+        }
+    }
+}
+
+// Clause names, for the error messages and completion
+const FILTER_CLAUSES: &[&str] = &["position", "nationality", "club", "league", "name", "min_count", "min_rating"];
+pub const FIND_CLAUSES: &[&str] = &["sort", "limit", "offset"];
+pub const STATS_CLAUSES: &[&str] = &["by", "top"];
+
+pub fn clauses(own: &[&'static str]) -> Vec<&'static str> {
+    own.iter().chain(FILTER_CLAUSES).copied().collect()
+}
+
+// Which players a find or stats looks at. Every clause must hold; a clause
+// with comma-separated values holds when any of them does.
+#[derive(Debug, Default, PartialEq)]
+pub struct Filters {
+    pub fields: Vec<(Field, Vec<String>)>,
+    pub name: Option<String>,
+    pub min_count: u32,
+    pub min_rating: Option<f32>,
+}
+
+impl Filters {
+    // Takes a filter clause, returns false for any other key
+    fn parse(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        if let Some(field) = Field::parse(key) {
+            let mut values: Vec<String> = value
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect();
+            // Positions take groups too, and unknown codes are refused
+            if field == Field::Position {
+                let mut codes = Vec::new();
+                for value in &values {
+                    codes.extend(positions::expand(value)?.into_iter().map(|code| code.to_string()));
+                }
+                values = codes;
+            }
+            self.fields.push((field, values));
+            return Ok(true);
+        }
+
+        match key {
+            "name" => self.name = Some(value.to_string()),
+            "min_count" => self.min_count = number(key, value)?,
+            "min_rating" => self.min_rating = Some(number(key, value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // The global ratings of the players that pass, in no particular order
+    pub fn matching<'t>(&self, tables: &'t Tables) -> Vec<&'t RatingPlayer> {
+        let name = self.name.as_ref().map(|name| name.to_lowercase());
+
+        candidates(tables, &self.fields)
+            .into_iter()
+            .filter_map(|id| tables.rating_table.search_non_mut(&id))
+            .filter(|rating| rating.num_ratings >= self.min_count)
+            .filter(|rating| self.min_rating.is_none_or(|min| rating.average() >= min))
+            .filter(|rating| match (&name, tables.players_table.search_non_mut(&rating.sofifa_id)) {
+                (Some(name), Some(player)) => {
+                    player.long_name.to_lowercase().contains(name)
+                        || player.short_name.to_lowercase().contains(name)
+                }
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .collect()
+    }
+}
+
+// A parsed `find`
+#[derive(Debug, PartialEq)]
+pub struct FindQuery {
+    pub filters: Filters,
+    // None sorts by rating, best first
+    pub sort: Option<String>,
+    pub descending: bool,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Default for FindQuery {
+    fn default() -> Self {
+        FindQuery {
+            filters: Filters::default(),
+            sort: None,
+            descending: false,
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} '{}', expected a number", key, value))
+}
+
+// "league=Spain Primera Division" -> ("league", "Spain Primera Division")
+fn split_clause(word: &str) -> Result<(String, &str), String> {
+    let (key, value) = match word.split_once('=') {
+        Some((key, value)) => (key.to_lowercase(), value.trim()),
+        None => return Err(format!("Expected a clause like key=value, found '{}'", word)),
+    };
+    if value.is_empty() {
+        return Err(format!("Missing value for {}", key));
+    }
+    Ok((key, value))
+}
+
+fn unknown_clause(key: &str, own: &[&'static str]) -> String {
+    format!("Unknown clause '{}', expected one of {}", key, clauses(own).join(", "))
+}
+
+// "position=ST,LW league='Spain Primera Division' sort=rating desc limit=20",
+// already split into words
+pub fn parse_find_query<S: AsRef<str>>(words: &[S]) -> Result<FindQuery, String> {
+    let mut query = FindQuery::default();
+    let mut sorted = false;
+
+    for word in words {
+        let word = word.as_ref();
+        // asc and desc go with the sort clause before them
+        match word.to_lowercase().as_str() {
+            "asc" | "desc" if sorted => {
+                query.descending = word.eq_ignore_ascii_case("desc");
+                continue;
+            }
+            "asc" | "desc" => return Err(format!("'{}' must follow a sort clause", word)),
+            _ => {}
+        }
+
+        let (key, value) = split_clause(word)?;
+        if query.filters.parse(&key, value)? {
+            continue;
+        }
+
+        match key.as_str() {
+            "sort" => {
+                let available = player_columns();
+                select_columns(&available, &[value])?;
+                query.sort = Some(value.to_string());
+                sorted = true;
+            }
+            "limit" => match number(&key, value)? {
+                0 => return Err("limit must be at least 1".to_string()),
+                limit => query.limit = limit,
+            },
+            "offset" => query.offset = number(&key, value)?,
+            _ => return Err(unknown_clause(&key, FIND_CLAUSES)),
+        }
+    }
+    Ok(query)
+}
+
+// Players of the indexes named by the filters, or every player when there
+// are none
+fn candidates(tables: &Tables, filters: &[(Field, Vec<String>)]) -> Vec<u32> {
+    let mut result: Option<Vec<u32>> = None;
+    for (field, values) in filters {
+        let index = field.index(tables);
+        let mut ids: Vec<u32> = values
+            .iter()
+            .flat_map(|value| index.get_id(value).unwrap_or_default())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        result = Some(match result {
+            Some(mut result) => {
+                result.retain(|id| ids.binary_search(id).is_ok());
+                result
+            }
+            None => ids,
+        });
+    }
+    result.unwrap_or_else(|| tables.players_table.iter().map(|(id, _)| *id).collect())
+}
+
+// Values of an indexed field starting with `prefix`, and their players
+pub fn players_with_prefix(tables: &Tables, field: Field, prefix: &str) -> (Vec<String>, Vec<u32>) {
+    let index = field.index(tables);
+    let values = index.get_words_starting_with(prefix);
+    let mut ids: Vec<u32> = values
+        .iter()
+        .flat_map(|value| index.get_id(value).unwrap_or_default())
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    (values, ids)
+}
+
+// A parsed `stats`: the filters pick the players, `by` groups them
+#[derive(Debug, PartialEq)]
+pub struct StatsQuery {
+    pub by: Field,
+    pub filters: Filters,
+    pub top: usize,
+}
+
+// "by=club position=GK min_count=100 top=10", already split into words
+pub fn parse_stats_query<S: AsRef<str>>(words: &[S]) -> Result<StatsQuery, String> {
+    let mut by = None;
+    let mut filters = Filters::default();
+    let mut top = DEFAULT_TOP;
+
+    for word in words {
+        let (key, value) = split_clause(word.as_ref())?;
+        if filters.parse(&key, value)? {
+            continue;
+        }
+
+        match key.as_str() {
+            "by" => match Field::parse(&value.to_lowercase()) {
+                Some(field) => by = Some(field),
+                None => {
+                    return Err(format!(
+                        "Cannot group by '{}', expected club, league, nationality or position",
+                        value
+                    ))
+                }
+            },
+            "top" => match number(&key, value)? {
+                0 => return Err("top must be at least 1".to_string()),
+                n => top = n,
+            },
+            _ => return Err(unknown_clause(&key, STATS_CLAUSES)),
+        }
+    }
+
+    match by {
+        Some(by) => Ok(StatsQuery { by, filters, top }),
+        None => Err("Missing by=club|league|nationality|position".to_string()),
+    }
+}
+
+// One group of a stats result. The rating is the mean of the averages of
+// the group's rated players.
+#[derive(Debug, Clone)]
+pub struct GroupStats {
+    pub group: String,
+    pub players: usize,
+    pub rated_players: usize,
+    pub ratings: u64,
+    pub rating: Option<f32>,
+    pub best_player: Option<String>,
+    rating_sum: f32,
+    best_rating: f32,
+}
+
+impl GroupStats {
+    fn new(group: String) -> Self {
+        GroupStats {
+            group,
+            players: 0,
+            rated_players: 0,
+            ratings: 0,
+            rating: None,
+            best_player: None,
+            rating_sum: 0.0,
+            best_rating: f32::MIN,
+        }
+    }
+
+    fn add(&mut self, rating: &RatingPlayer, player: &Player) {
+        self.players += 1;
+        if rating.num_ratings == 0 {
+            return;
+        }
+        self.rated_players += 1;
+        self.ratings += rating.num_ratings as u64;
+        self.rating_sum += rating.average();
+        self.rating = Some(self.rating_sum / self.rated_players as f32);
+        if rating.average() > self.best_rating {
+            self.best_rating = rating.average();
+            self.best_player = Some(player.short_name.clone());
+        }
+    }
+}
+
+// Every group of the matching players, best rated first (groups without
+// ratings last), cut to `top`. Also returns how many groups there were.
+pub fn run_stats_query(tables: &Tables, query: &StatsQuery) -> (Vec<GroupStats>, usize) {
+    let mut groups: BTreeMap<String, GroupStats> = BTreeMap::new();
+    for rating in query.filters.matching(tables) {
+        let player = match tables.players_table.search_non_mut(&rating.sofifa_id) {
+            Some(player) => player,
+            None => continue,
+        };
+        for value in query.by.values(player) {
+            groups
+                .entry(value.clone())
+                .or_insert_with(|| GroupStats::new(value))
+                .add(rating, player);
+        }
+    }
+
+    let total = groups.len();
+    let mut groups: Vec<GroupStats> = groups.into_values().collect();
+    groups.sort_by(|a, b| {
+        let rating = |g: &GroupStats| g.rating.map_or(Raw::Missing, Raw::Float);
+        compare(&rating(a), &rating(b), true).then(b.rated_players.cmp(&a.rated_players))
+    });
+    groups.truncate(query.top);
+    (groups, total)
+}
+
+// Missing values sort last whatever the direction
+fn compare(a: &Raw, b: &Raw, descending: bool) -> Ordering {
+    let ordering = match (a, b) {
+        (Raw::Missing, Raw::Missing) => return Ordering::Equal,
+        (Raw::Missing, _) => return Ordering::Greater,
+        (_, Raw::Missing) => return Ordering::Less,
+        (Raw::Text(x), Raw::Text(y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+        (Raw::Int(x), Raw::Int(y)) => x.cmp(y),
+        (Raw::Float(x), Raw::Float(y)) => x.total_cmp(y),
+        // Columns hold a single kind, this is only for completeness
+        _ => Ordering::Equal,
+    };
+    if descending {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+// The global ratings of the matching players, sorted, before limit and
+// offset are applied
+pub fn run_find_query<'t>(tables: &'t Tables, query: &FindQuery) -> Vec<&'t RatingPlayer> {
+    let mut ratings = query.filters.matching(tables);
+
+    match &query.sort {
+        Some(column) => {
+            let available = player_columns();
+            let column = available.iter().find(|c| c.name == column).unwrap();
+            let mut rows: Vec<PlayerRow> = ratings
+                .iter()
+                .map(|rating| PlayerRow::new(rating, &tables.players_table, &tables.rating_table))
+                .collect();
+            rows.sort_by(|a, b| compare(&(column.extract)(a), &(column.extract)(b), query.descending));
+            ratings = rows.into_iter().map(|row| row.rating).collect();
+        }
+        None => sort_players_by_rating(&mut ratings),
+    }
+    ratings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_find_query() {
+        let query = parse_find_query(&[
+            "position=ST,LW",
+            "country=Brazil",
+            "league=Spain Primera Division",
+            "min_count=500",
+            "sort=overall",
+            "DESC",
+            "limit=5",
+        ])
+        .unwrap();
+        assert_eq!(
+            query.filters.fields,
+            vec![
+                (Field::Position, vec!["ST".to_string(), "LW".to_string()]),
+                (Field::Nationality, vec!["Brazil".to_string()]),
+                (Field::League, vec!["Spain Primera Division".to_string()]),
+            ]
+        );
+        assert_eq!(query.filters.min_count, 500);
+        assert_eq!(query.sort.as_deref(), Some("overall"));
+        assert!(query.descending);
+        assert_eq!(query.limit, 5);
+
+        // Paging past the end is left to the command, which clamps it
+        let query = parse_find_query(&["offset=1", "limit=18446744073709551615"]).unwrap();
+        assert_eq!((query.offset, query.limit), (1, usize::MAX));
+
+        assert!(parse_find_query(&["shoe=42"]).unwrap_err().starts_with("Unknown clause 'shoe'"));
+        assert!(parse_find_query(&["sort=shoe_size"]).unwrap_err().starts_with("Unknown column(s)"));
+        assert!(parse_find_query(&["desc"]).is_err());
+        assert!(parse_find_query(&["min_count=many"]).is_err());
+        assert!(parse_find_query(&["Brazil"]).is_err());
+        assert!(parse_find_query(&["club="]).is_err());
+        assert!(parse_find_query(&["position=XX"]).unwrap_err().starts_with("Unknown position 'XX'"));
+    }
+
+    #[test]
+    fn test_parse_stats_query() {
+        let query = parse_stats_query(&["by=club", "position=GK", "min_count=100", "top=5"]).unwrap();
+        assert_eq!(query.by, Field::Club);
+        assert_eq!(query.filters.fields, vec![(Field::Position, vec!["GK".to_string()])]);
+        assert_eq!(query.filters.min_count, 100);
+        assert_eq!(query.top, 5);
+
+        assert_eq!(parse_stats_query(&["by=country"]).unwrap().by, Field::Nationality);
+        assert!(parse_stats_query(&["position=GK"]).unwrap_err().starts_with("Missing by="));
+        assert!(parse_stats_query(&["by=age"]).unwrap_err().starts_with("Cannot group by 'age'"));
+        assert!(parse_stats_query(&["by=club", "limit=3"]).unwrap_err().starts_with("Unknown clause 'limit'"));
+    }
+
+    #[test]
+    fn test_run_find_query() {
+        let mut tables = Tables::new();
+        let mut quarantine = Quarantine::new(false);
+        for (id, positions, nationality, overall) in [
+            (1, "ST", "Brazil", Some(90)),
+            (2, "ST, LW", "Brazil", None),
+            (3, "LW", "Brazil", Some(80)),
+            (4, "ST", "France", Some(85)),
+        ] {
+            tables.add_player(Player {
+                sofifa_id: id,
+                short_name: format!("P{}", id),
+                long_name: format!("Player {}", id),
+                player_positions: positions.to_string(),
+                nationality: nationality.to_string(),
+                overall,
+                ..Default::default()
+            });
+            for user_id in 0..id {
+                let rating = RatingFile {
+                    user_id,
+                    sofifa_id: id,
+                    rating: id as f32,
+                };
+                tables.add_rating(rating, DedupPolicy::CountAll, &mut quarantine);
+            }
+        }
+        let ids = |words: &[&str]| -> Vec<u32> {
+            let query = parse_find_query(words).unwrap();
+            run_find_query(&tables, &query).iter().map(|r| r.sofifa_id).collect()
+        };
+
+        assert_eq!(ids(&[]), vec![4, 3, 2, 1]);
+        assert_eq!(ids(&["position=st", "nationality=brazil"]), vec![2, 1]);
+        assert_eq!(ids(&["position=ST,LW", "min_count=2"]), vec![4, 3, 2]);
+        assert_eq!(ids(&["position=att", "nationality=France"]), vec![4]);
+        assert_eq!(ids(&["sort=overall", "desc"]), vec![1, 4, 3, 2]);
+        assert_eq!(ids(&["sort=overall"]), vec![3, 4, 1, 2]);
+        assert_eq!(ids(&["name=player 3"]), vec![3]);
+        assert!(ids(&["club=Nowhere"]).is_empty());
+
+        let (values, ids) = players_with_prefix(&tables, Field::Nationality, "fr");
+        assert_eq!(values, vec!["france"]);
+        assert_eq!(ids, vec![4]);
+        let (values, ids) = players_with_prefix(&tables, Field::Position, "");
+        assert_eq!(values.len(), 2);
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        let stats = |words: &[&str]| {
+            let query = parse_stats_query(words).unwrap();
+            run_stats_query(&tables, &query)
+        };
+        let (groups, total) = stats(&["by=nationality"]);
+        assert_eq!(total, 2);
+        assert_eq!(groups[0].group, "France");
+        assert_eq!(groups[1].group, "Brazil");
+        assert_eq!((groups[1].players, groups[1].rated_players, groups[1].ratings), (3, 3, 6));
+        assert_eq!(groups[1].rating, Some(2.0));
+        assert_eq!(groups[1].best_player.as_deref(), Some("P3"));
+
+        // A player counts once for each of their positions
+        let (groups, total) = stats(&["by=position", "nationality=Brazil", "top=1"]);
+        assert_eq!(total, 2);
+        assert_eq!(groups.len(), 1);
+        assert_eq!((groups[0].group.as_str(), groups[0].players), ("LW", 2));
+        assert_eq!(groups[0].rating, Some(2.5));
+    }
+}
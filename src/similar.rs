@@ -0,0 +1,118 @@
+use crate::loader::Tables;
+use crate::structs::*;
+
+// Attributes compared between players, when both have them
+const ATTRIBUTES: &[fn(&Player) -> Option<u32>] = &[
+    |p| p.overall,
+    |p| p.pace,
+    |p| p.shooting,
+    |p| p.passing,
+    |p| p.dribbling,
+    |p| p.defending,
+    |p| p.physic,
+];
+
+// Root mean square difference over the attributes both players have, None
+// when they have none in common
+fn attribute_distance(a: &Player, b: &Player) -> Option<f32> {
+    let differences: Vec<f32> = ATTRIBUTES
+        .iter()
+        .filter_map(|attribute| match (attribute(a), attribute(b)) {
+            (Some(x), Some(y)) => Some(x as f32 - y as f32),
+            _ => None,
+        })
+        .collect();
+    if differences.is_empty() {
+        return None;
+    }
+    let mean = differences.iter().map(|d| d * d).sum::<f32>() / differences.len() as f32;
+    Some(mean.sqrt())
+}
+
+// Players sharing at least one position with `sofifa_id`, closest first.
+// They are ranked by attribute distance (players without comparable
+// attributes come last), then by how many positions they share, then by
+// how close their global rating is.
+pub fn similar_players(tables: &Tables, sofifa_id: u32, n: usize) -> Vec<u32> {
+    let player = match tables.players_table.search_non_mut(&sofifa_id) {
+        Some(player) => player,
+        None => return Vec::new(),
+    };
+    let rating = |id: &u32| {
+        tables
+            .rating_table
+            .search_non_mut(id)
+            .map(|r| r.average())
+            .unwrap_or(0.0)
+    };
+    let own_positions = player.positions();
+    let own_rating = rating(&sofifa_id);
+
+    let mut candidates: Vec<u32> = own_positions
+        .iter()
+        .flat_map(|position| tables.position_player.get_id(position).unwrap_or_default())
+        .filter(|id| *id != sofifa_id)
+        .collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let mut ranked: Vec<(u32, Option<f32>, usize, f32)> = candidates
+        .into_iter()
+        .filter_map(|id| {
+            let other = tables.players_table.search_non_mut(&id)?;
+            let shared = other
+                .positions()
+                .iter()
+                .filter(|p| own_positions.contains(p))
+                .count();
+            let gap = (rating(&id) - own_rating).abs();
+            Some((id, attribute_distance(player, other), shared, gap))
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        let distance = match (a.1, b.1) {
+            (Some(x), Some(y)) => x.total_cmp(&y),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        distance
+            .then(b.2.cmp(&a.2))
+            .then(a.3.total_cmp(&b.3))
+            .then(a.0.cmp(&b.0))
+    });
+    ranked.into_iter().take(n).map(|(id, ..)| id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(sofifa_id: u32, positions: &str, pace: Option<u32>) -> Player {
+        Player {
+            sofifa_id,
+            long_name: format!("Player {}", sofifa_id),
+            player_positions: positions.to_string(),
+            pace,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_similar_players() {
+        let mut tables = Tables::new();
+        tables.add_player(player(1, "ST, LW", Some(90)));
+        tables.add_player(player(2, "ST", Some(70)));
+        tables.add_player(player(3, "LW, ST", Some(60)));
+        tables.add_player(player(4, "ST", None));
+        tables.add_player(player(5, "GK", Some(90)));
+
+        // 2 is closer on pace, 3 shares more positions but is further away,
+        // 4 cannot be compared and 5 plays elsewhere
+        assert_eq!(similar_players(&tables, 1, 10), vec![2, 3, 4]);
+        assert_eq!(similar_players(&tables, 1, 1), vec![2]);
+        assert!(similar_players(&tables, 5, 10).is_empty());
+        assert!(similar_players(&tables, 99, 10).is_empty());
+    }
+}
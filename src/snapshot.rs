@@ -0,0 +1,581 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::config::Config;
+use crate::hash_table::HashMap;
+use crate::loader::Tables;
+use crate::structs::*;
+use crate::trie::Trie;
+
+// Binary snapshot of every table built at startup.
+//
+// Layout (little endian):
+//   magic "TFCPDSNP" | schema version u32 | source fingerprint |
+//   payload length u64 | payload checksum u64 | payload
+//
+// Bump SCHEMA_VERSION whenever the encoding of a table changes.
+const MAGIC: &[u8; 8] = b"TFCPDSNP";
+const SCHEMA_VERSION: u32 = 5;
+// Bucket counts are not element counts, so they are checked against a sanity
+// bound instead of the bytes left
+const MAX_BUCKETS: u64 = 1 << 24;
+
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn opt_u32(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u32(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub fn opt_str(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.str(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err("snapshot is truncated".to_string());
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn len(&mut self) -> Result<usize, String> {
+        let len = self.u64()? as usize;
+        // Every encoded element takes at least one byte
+        if len > self.buf.len() - self.pos {
+            return Err("snapshot is truncated".to_string());
+        }
+        Ok(len)
+    }
+
+    pub fn str(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    pub fn opt_u32(&mut self) -> Result<Option<u32>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u32()?)),
+        }
+    }
+
+    pub fn opt_str(&mut self) -> Result<Option<String>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.str()?)),
+        }
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, enc: &mut Encoder);
+}
+
+pub trait Decode: Sized {
+    fn decode(dec: &mut Decoder) -> Result<Self, String>;
+}
+
+impl Encode for Player {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.sofifa_id);
+        enc.str(&self.short_name);
+        enc.str(&self.long_name);
+        enc.str(&self.player_positions);
+        enc.str(&self.nationality);
+        enc.str(&self.club_name);
+        enc.str(&self.league_name);
+        enc.opt_u32(self.age);
+        enc.opt_u32(self.height_cm);
+        enc.opt_u32(self.weight_kg);
+        enc.opt_u32(self.overall);
+        enc.opt_u32(self.potential);
+        enc.opt_u32(self.value_eur);
+        enc.opt_u32(self.wage_eur);
+        enc.opt_str(self.preferred_foot.as_deref());
+        enc.opt_u32(self.weak_foot);
+        enc.opt_u32(self.skill_moves);
+        enc.opt_u32(self.pace);
+        enc.opt_u32(self.shooting);
+        enc.opt_u32(self.passing);
+        enc.opt_u32(self.dribbling);
+        enc.opt_u32(self.defending);
+        enc.opt_u32(self.physic);
+    }
+}
+
+impl Decode for Player {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        Ok(Player {
+            sofifa_id: dec.u32()?,
+            short_name: dec.str()?,
+            long_name: dec.str()?,
+            player_positions: dec.str()?,
+            nationality: dec.str()?,
+            club_name: dec.str()?,
+            league_name: dec.str()?,
+            age: dec.opt_u32()?,
+            height_cm: dec.opt_u32()?,
+            weight_kg: dec.opt_u32()?,
+            overall: dec.opt_u32()?,
+            potential: dec.opt_u32()?,
+            value_eur: dec.opt_u32()?,
+            wage_eur: dec.opt_u32()?,
+            preferred_foot: dec.opt_str()?,
+            weak_foot: dec.opt_u32()?,
+            skill_moves: dec.opt_u32()?,
+            pace: dec.opt_u32()?,
+            shooting: dec.opt_u32()?,
+            passing: dec.opt_u32()?,
+            dribbling: dec.opt_u32()?,
+            defending: dec.opt_u32()?,
+            physic: dec.opt_u32()?,
+        })
+    }
+}
+
+impl Encode for RatingPlayer {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.sofifa_id);
+        enc.f32(self.rating_sum);
+        enc.u32(self.num_ratings);
+    }
+}
+
+impl Decode for RatingPlayer {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        Ok(RatingPlayer {
+            sofifa_id: dec.u32()?,
+            rating_sum: dec.f32()?,
+            num_ratings: dec.u32()?,
+        })
+    }
+}
+
+impl Encode for RatingHistogram {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.sofifa_id);
+        for count in self.buckets {
+            enc.u32(count);
+        }
+    }
+}
+
+impl Decode for RatingHistogram {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        let mut histogram = RatingHistogram::new(dec.u32()?);
+        for bucket in histogram.buckets.iter_mut() {
+            *bucket = dec.u32()?;
+        }
+        Ok(histogram)
+    }
+}
+
+impl Encode for PlayerTags {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.sofifa_id);
+        enc.len(self.tags.len());
+        for (tag, count) in &self.tags {
+            enc.str(tag);
+            enc.u32(*count);
+        }
+    }
+}
+
+impl Decode for PlayerTags {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        let mut tags = PlayerTags::new(dec.u32()?);
+        let len = dec.len()?;
+        for _ in 0..len {
+            tags.tags.push((dec.str()?, dec.u32()?));
+        }
+        Ok(tags)
+    }
+}
+
+impl Encode for User {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.user_id);
+        enc.len(self.ratings.len());
+        for rating in &self.ratings {
+            rating.encode(enc);
+        }
+    }
+}
+
+impl Decode for User {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        let user_id = dec.u32()?;
+        let len = dec.len()?;
+        let mut ratings = Vec::with_capacity(len);
+        for _ in 0..len {
+            ratings.push(RatingPlayer::decode(dec)?);
+        }
+        Ok(User { user_id, ratings })
+    }
+}
+
+impl Encode for Tags {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u32(self.user_id);
+        enc.u32(self.sofifa_id);
+        enc.str(&self.tag);
+    }
+}
+
+impl Decode for Tags {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        Ok(Tags {
+            user_id: dec.u32()?,
+            sofifa_id: dec.u32()?,
+            tag: dec.str()?,
+        })
+    }
+}
+
+impl Encode for Quarantine {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u8(self.keep as u8);
+        enc.u64(self.orphan_ratings as u64);
+        enc.u64(self.orphan_tags as u64);
+        enc.u64(self.unknown_players as u64);
+        self.ratings.encode(enc);
+        enc.len(self.tags.len());
+        for tag in &self.tags {
+            tag.encode(enc);
+        }
+    }
+}
+
+impl Decode for Quarantine {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        let mut quarantine = Quarantine::new(dec.u8()? != 0);
+        quarantine.orphan_ratings = dec.u64()? as usize;
+        quarantine.orphan_tags = dec.u64()? as usize;
+        quarantine.unknown_players = dec.u64()? as usize;
+        quarantine.ratings = HashMap::decode(dec)?;
+        let len = dec.len()?;
+        for _ in 0..len {
+            quarantine.tags.push(Tags::decode(dec)?);
+        }
+        Ok(quarantine)
+    }
+}
+
+impl<V: Encode> Encode for HashMap<u32, V> {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.u64(self.modulo() as u64);
+        enc.len(self.iter().count());
+        for (key, value) in self.iter() {
+            enc.u32(*key);
+            value.encode(enc);
+        }
+    }
+}
+
+impl<V: Decode> Decode for HashMap<u32, V> {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        let modulo = dec.u64()?;
+        if modulo == 0 || modulo > MAX_BUCKETS {
+            return Err(format!("hash table with {} buckets", modulo));
+        }
+        let len = dec.len()?;
+
+        let mut map = HashMap::new(modulo as usize);
+        for _ in 0..len {
+            let key = dec.u32()?;
+            map.insert(key, V::decode(dec)?);
+        }
+        Ok(map)
+    }
+}
+
+// Size and modification time of each source file, so a snapshot taken from
+// older CSVs is detected as stale, plus the dedup policy, quarantine setting
+// and column map the tables were built with
+fn fingerprint(config: &Config) -> Vec<u64> {
+    let columns = format!("{:?}", config.column_map);
+    let mut fingerprint = vec![
+        config.dedup as u64,
+        config.quarantine as u64,
+        checksum(columns.as_bytes()),
+    ];
+    for path in [&config.players, &config.ratings, &config.tags] {
+        let metadata = fs::metadata(path).ok();
+        let modified = metadata
+            .as_ref()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        fingerprint.push(metadata.map(|m| m.len()).unwrap_or(0));
+        fingerprint.push(modified);
+    }
+    fingerprint
+}
+
+// FNV-1a, enough to catch a corrupted or truncated file
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn save_snapshot<P: AsRef<Path>>(
+    path: P,
+    tables: &Tables,
+    quarantine: &Quarantine,
+    config: &Config,
+) -> Result<(), String> {
+    let mut payload = Encoder::new();
+    tables.players_table.encode(&mut payload);
+    tables.rating_table.encode(&mut payload);
+    tables.user_table.encode(&mut payload);
+    tables.name_index.encode(&mut payload);
+    tables.tag_player.encode(&mut payload);
+    tables.position_player.encode(&mut payload);
+    tables.nationality_player.encode(&mut payload);
+    tables.club_player.encode(&mut payload);
+    tables.league_player.encode(&mut payload);
+    tables.histograms.encode(&mut payload);
+    tables.player_tags.encode(&mut payload);
+    quarantine.encode(&mut payload);
+    let payload = payload.into_bytes();
+
+    let mut header = Encoder::new();
+    header.u32(SCHEMA_VERSION);
+    let fingerprint = fingerprint(config);
+    header.len(fingerprint.len());
+    for value in fingerprint {
+        header.u64(value);
+    }
+    header.len(payload.len());
+    header.u64(checksum(&payload));
+
+    let write = || -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&header.into_bytes())?;
+        file.write_all(&payload)?;
+        file.flush()
+    };
+    write().map_err(|e| e.to_string())
+}
+
+// Any mismatch (format, schema version, source files, checksum) is returned
+// as an error so the caller can fall back to the CSV files
+pub fn load_snapshot<P: AsRef<Path>>(path: P, config: &Config) -> Result<(Tables, Quarantine), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("not a snapshot file".to_string());
+    }
+    let mut dec = Decoder::new(&bytes[MAGIC.len()..]);
+
+    let version = dec.u32()?;
+    if version != SCHEMA_VERSION {
+        return Err(format!(
+            "schema version {} does not match {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    let len = dec.len()?;
+    let mut stored = Vec::with_capacity(len);
+    for _ in 0..len {
+        stored.push(dec.u64()?);
+    }
+    if stored != fingerprint(config) {
+        return Err("source files changed since the snapshot was taken".to_string());
+    }
+
+    let payload_len = dec.len()?;
+    let expected = dec.u64()?;
+    let payload = dec.take(payload_len)?;
+    if checksum(payload) != expected {
+        return Err("checksum mismatch".to_string());
+    }
+
+    let mut dec = Decoder::new(payload);
+    let tables = Tables {
+        players_table: HashMap::decode(&mut dec)?,
+        rating_table: HashMap::decode(&mut dec)?,
+        user_table: HashMap::decode(&mut dec)?,
+        name_index: Trie::decode(&mut dec)?,
+        tag_player: Trie::decode(&mut dec)?,
+        position_player: Trie::decode(&mut dec)?,
+        nationality_player: Trie::decode(&mut dec)?,
+        club_player: Trie::decode(&mut dec)?,
+        league_player: Trie::decode(&mut dec)?,
+        histograms: HashMap::decode(&mut dec)?,
+        player_tags: HashMap::decode(&mut dec)?,
+        rated: None,
+    };
+    Ok((tables, Quarantine::decode(&mut dec)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables_round_trip() {
+        let mut ratings: HashMap<u32, RatingPlayer> = HashMap::new(7);
+        for id in [3, 10, 17, 4] {
+            ratings.insert(
+                id,
+                RatingPlayer {
+                    sofifa_id: id,
+                    rating_sum: id as f32 * 1.5,
+                    num_ratings: id,
+                },
+            );
+        }
+
+        let mut trie = Trie::new();
+        trie.insert_with_id("Messi", 1);
+        trie.insert_with_id("Mbappe", 2);
+        trie.insert_with_id("Messi", 3);
+
+        let mut enc = Encoder::new();
+        ratings.encode(&mut enc);
+        trie.encode(&mut enc);
+        let bytes = enc.into_bytes();
+
+        let mut dec = Decoder::new(&bytes);
+        let decoded: HashMap<u32, RatingPlayer> = HashMap::decode(&mut dec).unwrap();
+        let decoded_trie = Trie::decode(&mut dec).unwrap();
+
+        let keys: Vec<u32> = decoded.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, ratings.iter().map(|(k, _)| *k).collect::<Vec<u32>>());
+        assert_eq!(decoded.search_non_mut(&17).unwrap().num_ratings, 17);
+        assert_eq!(decoded_trie.get_id("messi"), Some(vec![1, 3]));
+        assert_eq!(
+            decoded_trie.get_words_starting_with("m"),
+            trie.get_words_starting_with("m")
+        );
+
+        // Cut short, the decoder must fail instead of panicking
+        let mut dec = Decoder::new(&bytes[..bytes.len() - 3]);
+        let _ = HashMap::<u32, RatingPlayer>::decode(&mut dec).unwrap();
+        assert!(Trie::decode(&mut dec).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_of_loaded_tables() {
+        let dir = std::env::temp_dir().join(format!("tf_cpd_snapshot_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("players.csv"),
+            "sofifa_id,short_name,long_name,player_positions,nationality,club_name,league_name\n\
+             1,A,Alpha,\"ST, LW\",Brazil,Club,League\n\
+             2,B,Beta,GK,Spain,Club,League\n",
+        )
+        .unwrap();
+        fs::write(dir.join("rating.csv"), "user_id,sofifa_id,rating\n1,1,4.5\n2,1,3.0\n2,2,2.0\n3,9,1.0\n").unwrap();
+        fs::write(dir.join("tags.csv"), "user_id,sofifa_id,tag\n1,1,Fast\n2,9,Slow\n").unwrap();
+
+        let args = ["--data-dir", dir.to_str().unwrap(), "--threads", "1", "--quarantine"];
+        let config = Config::from_args_with_env(args.iter().map(|s| s.to_string()), |_| None).unwrap();
+        let (tables, _, quarantine) = crate::loader::load_tables(&config);
+
+        // A handful of rows is far smaller than the bucket counts
+        let path = dir.join("tables.snap");
+        save_snapshot(&path, &tables, &quarantine, &config).unwrap();
+        let (loaded, loaded_quarantine) = load_snapshot(&path, &config).unwrap();
+
+        let average = |tables: &Tables, id| tables.rating_table.search_non_mut(&id).map(|r| r.average());
+        assert_eq!(average(&loaded, 1), average(&tables, 1));
+        assert_eq!(average(&loaded, 2), Some(2.0));
+        assert_eq!(loaded.players_table.search_non_mut(&2).unwrap().long_name, "Beta");
+        assert_eq!(loaded.user_table.search_non_mut(&2).unwrap().ratings.len(), 2);
+        assert_eq!(loaded.name_index.get_id("alpha"), Some(vec![1]));
+        assert_eq!(loaded.position_player.get_id("LW"), tables.position_player.get_id("LW"));
+        assert_eq!(loaded.player_tags.search_non_mut(&1).unwrap().top(1), vec![("Fast", 1)]);
+        assert_eq!(loaded_quarantine.orphan_ratings, 1);
+        assert_eq!(loaded_quarantine.tags.len(), 1);
+        assert!(loaded_quarantine.ratings.search_non_mut(&9).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,89 +1,323 @@
 use std::cmp::Ordering;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
 use crate::hash_table;
 use crate::structs::*;
 
 
-pub fn sort_user_global_ratings(user_rating: &mut Vec<RatingPlayer>, rating_table: &hash_table::HashMap<u32, RatingPlayer>) {
+pub fn sort_user_global_ratings(user_rating: &mut [RatingPlayer], rating_table: &hash_table::HashMap<u32, RatingPlayer>) {
+    sort_by_key_desc(user_rating, |rating| {
+        match rating_table.search_non_mut(&rating.sofifa_id) {
+            Some(global) if global.num_ratings > 0 => global.average() + rating.average() * 10.0,
+            _ => 0.0, // for players with no ratings
+        }
+    })
+}
 
-    selection_sort(user_rating,|a, b| {
-        let global_rating_info_a = rating_table.search_non_mut(&a.sofifa_id).unwrap();
-        let global_rating_info_b = rating_table.search_non_mut(&b.sofifa_id).unwrap();
+pub fn sort_user_ratings(user_rating: &mut [RatingPlayer]) {
+    sort_by_key_desc(user_rating, RatingPlayer::average)
+}
 
-        let user_rating_a = a.rating_sum / (a.num_ratings as f32);
-        let user_rating_b = b.rating_sum / (b.num_ratings as f32);
+pub fn sort_players_by_rating(players: &mut [&RatingPlayer]) {
+    sort_by_key_desc(players, |rating| rating.average())
+}
 
-        let avg_a = if global_rating_info_a.num_ratings > 0 {
-            (global_rating_info_a.rating_sum / global_rating_info_a.num_ratings as f32) + user_rating_a * 10.0
-        } else {
-            0.0 // for players with no ratings
-        };
+// Decorate-sort-undecorate: `key` runs once per element instead of once per
+// comparison, then the slice is permuted in place. Stable, highest key first.
+pub fn sort_by_key_desc<T, K, F>(items: &mut [T], key: F)
+where
+    K: PartialOrd,
+    F: FnMut(&T) -> K,
+{
+    let keys: Vec<K> = items.iter().map(key).collect();
 
-        let avg_b = if global_rating_info_b.num_ratings > 0 {
-            (global_rating_info_b.rating_sum / global_rating_info_b.num_ratings as f32) + user_rating_b * 10.0
-        } else {
-            0.0
-        };
+    let indices: Vec<usize> = (0..items.len()).collect();
+    let mut order = merge_sort(indices, &mut |&a: &usize, &b: &usize| {
+        keys[b].partial_cmp(&keys[a]).unwrap_or(Ordering::Equal)
+    });
 
-        avg_b.partial_cmp(&avg_a).unwrap_or(Ordering::Equal)
-    })
+    apply_permutation(items, &mut order);
 }
 
-pub fn sort_user_ratings(user_rating: &mut Vec<RatingPlayer>) {
-    selection_sort(user_rating, |a, b| {
-        let avg_a = if a.num_ratings > 0 {
-            a.rating_sum / a.num_ratings as f32
-        } else {
-            0.0 // for players with no ratings
+// Moves items[order[i]] to position i, following each cycle once
+fn apply_permutation<T>(items: &mut [T], order: &mut [usize]) {
+    for start in 0..order.len() {
+        let mut i = start;
+        while order[i] != i {
+            let next = order[i];
+            order[i] = i;
+            if next == start {
+                break;
+            }
+            items.swap(i, next);
+            i = next;
+        }
+    }
+}
+
+pub fn merge_sort<T, F>(arr: Vec<T>, compare: &mut F) -> Vec<T>
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    if arr.len() <= 1 {
+        return arr;
+    }
+
+    let mut left = arr;
+    let right = left.split_off(left.len() / 2);
+
+    let left = merge_sort(left, compare);
+    let right = merge_sort(right, compare);
+
+    merge(left, right, compare)
+}
+
+fn merge<T, F>(left: Vec<T>, right: Vec<T>, compare: &mut F) -> Vec<T>
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    let mut result = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+
+    loop {
+        let take_left = match (left.peek(), right.peek()) {
+            (Some(a), Some(b)) => compare(b, a) != Ordering::Less, // keeps it stable
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
         };
 
-        let avg_b = if b.num_ratings > 0 {
-            b.rating_sum / b.num_ratings as f32
+        if take_left {
+            result.extend(left.next());
         } else {
-            0.0
-        };
+            result.extend(right.next());
+        }
+    }
 
-        avg_b.partial_cmp(&avg_a).unwrap_or(Ordering::Equal)
-    })
+    result
 }
 
+// Same as merge_sort, but the two halves are sorted on separate threads until
+// `threads` is exhausted
+pub fn parallel_merge_sort<T, F>(arr: Vec<T>, compare: &F, threads: usize) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    if threads <= 1 || arr.len() <= PARALLEL_SORT_MIN_LEN {
+        return merge_sort(arr, &mut |a: &T, b: &T| compare(a, b));
+    }
 
+    let mut left = arr;
+    let right = left.split_off(left.len() / 2);
 
-pub fn sort_players_by_rating(players: &mut Vec<&RatingPlayer>) {
+    let (left, right) = std::thread::scope(|scope| {
+        let handle = scope.spawn(|| parallel_merge_sort(right, compare, threads / 2));
+        let left = parallel_merge_sort(left, compare, threads - threads / 2);
+        (left, handle.join().unwrap())
+    });
 
-    selection_sort(players, |a, b| {
-        let avg_a = if a.num_ratings > 0 {
-            a.rating_sum / a.num_ratings as f32
-        } else {
-            0.0 // for players with no ratings
-        };
+    merge(left, right, &mut |a: &T, b: &T| compare(a, b))
+}
 
-        let avg_b = if b.num_ratings > 0 {
-            b.rating_sum / b.num_ratings as f32
-        } else {
-            0.0
-        };
+const PARALLEL_SORT_MIN_LEN: usize = 4_096; // below this spawning costs more than it saves
 
-        avg_b.partial_cmp(&avg_a).unwrap_or(Ordering::Equal)
-    })
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn run_path(dir: &Path) -> PathBuf {
+    let run = RUN_COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+    dir.join(format!("tf_cpd_run_{}_{}.csv", std::process::id(), run))
+}
+
+fn write_run<T: Serialize>(dir: &Path, run: &[T]) -> Result<PathBuf, Box<dyn Error>> {
+    let path = run_path(dir);
+    let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(&path)?));
+
+    for record in run {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+
+    Ok(path)
+}
+
+// External k-way merge sort: records are read in runs of `run_size`, each run is
+// sorted in memory and spilled to a temp file, then all runs are merged and
+// streamed to `output` in order. Only one run plus one record per run is held
+// in memory at a time.
+pub fn external_merge_sort<T, E, I, C, O>(
+    records: I,
+    run_size: usize,
+    temp_dir: &Path,
+    threads: usize,
+    compare: C,
+    mut output: O,
+) -> Result<usize, Box<dyn Error>>
+where
+    T: Serialize + DeserializeOwned + Send,
+    E: Into<Box<dyn Error>>,
+    I: IntoIterator<Item = Result<T, E>>,
+    C: Fn(&T, &T) -> Ordering + Sync,
+    O: FnMut(T),
+{
+    let run_size = run_size.max(1);
+    let mut runs: Vec<PathBuf> = Vec::new();
+    let mut buffer: Vec<T> = Vec::with_capacity(run_size);
+    let mut total = 0;
+
+    let result = (|| {
+        for record in records {
+            buffer.push(record.map_err(Into::into)?);
+            total += 1;
+
+            if buffer.len() == run_size {
+                let sorted = parallel_merge_sort(std::mem::take(&mut buffer), &compare, threads);
+                runs.push(write_run(temp_dir, &sorted)?);
+            }
+        }
+
+        // Everything fit in a single run, no need to touch the disk
+        if runs.is_empty() {
+            for record in parallel_merge_sort(std::mem::take(&mut buffer), &compare, threads) {
+                output(record);
+            }
+            return Ok(total);
+        }
+
+        if !buffer.is_empty() {
+            let sorted = parallel_merge_sort(std::mem::take(&mut buffer), &compare, threads);
+            runs.push(write_run(temp_dir, &sorted)?);
+        }
+
+        merge_runs(&runs, &compare, &mut output)?;
+        Ok(total)
+    })();
+
+    for run in &runs {
+        let _ = fs::remove_file(run);
+    }
+
+    result
 }
 
+fn merge_runs<T, C, O>(runs: &[PathBuf], compare: &C, output: &mut O) -> Result<(), Box<dyn Error>>
+where
+    T: DeserializeOwned,
+    C: Fn(&T, &T) -> Ordering,
+    O: FnMut(T),
+{
+    let mut readers = Vec::with_capacity(runs.len());
+    for run in runs {
+        let reader = csv::Reader::from_reader(BufReader::new(File::open(run)?));
+        readers.push(reader.into_deserialize::<T>());
+    }
+
+    let mut heads: Vec<Option<T>> = Vec::with_capacity(readers.len());
+    for reader in readers.iter_mut() {
+        heads.push(reader.next().transpose()?);
+    }
 
-    pub fn selection_sort<T, F>(arr: &mut Vec<T>, mut compare: F)
-    where
-        F: FnMut(&T, &T) -> Ordering,
-    {
-        let n = arr.len();
-    
-        for i in 0..n {
-            let mut best_index = i;
-    
-            for j in (i + 1)..n {
-                if compare(&arr[j], &arr[best_index]) == Ordering::Less {
-                    best_index = j;
+    // The number of runs is small, so a linear scan over the heads is enough
+    loop {
+        let mut best: Option<usize> = None;
+
+        for (i, head) in heads.iter().enumerate() {
+            if let Some(record) = head {
+                let is_better = match best {
+                    Some(b) => compare(record, heads[b].as_ref().unwrap()) == Ordering::Less,
+                    None => true,
+                };
+                if is_better {
+                    best = Some(i);
                 }
             }
-    
-            arr.swap(i, best_index);
         }
-    }
\ No newline at end of file
+
+        match best {
+            Some(i) => {
+                let next = readers[i].next().transpose()?;
+                let record = std::mem::replace(&mut heads[i], next).unwrap();
+                output(record);
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_sort() {
+        let sorted = merge_sort(vec![5, 1, 4, 2, 3, 2], &mut |a: &i32, b: &i32| a.cmp(b));
+        assert_eq!(sorted, vec![1, 2, 2, 3, 4, 5]);
+
+        let empty: Vec<i32> = merge_sort(Vec::new(), &mut |a: &i32, b: &i32| a.cmp(b));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_external_merge_sort_spills_runs() {
+        let records: Vec<Result<RatingFile, csv::Error>> = (0..10)
+            .map(|i| {
+                Ok(RatingFile {
+                    user_id: (i * 7) % 10,
+                    sofifa_id: i,
+                    rating: 1.0,
+                })
+            })
+            .collect();
+
+        let mut users = Vec::new();
+        let total = external_merge_sort(
+            records,
+            3,
+            &std::env::temp_dir(),
+            2,
+            |a: &RatingFile, b: &RatingFile| a.user_id.cmp(&b.user_id),
+            |r: RatingFile| users.push(r.user_id),
+        )
+        .unwrap();
+
+        assert_eq!(total, 10);
+        assert_eq!(users, (0..10).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_parallel_merge_sort_matches_sequential() {
+        let values: Vec<u32> = (0..20_000).map(|i| (i * 7919) % 10_007).collect();
+
+        let expected = merge_sort(values.clone(), &mut |a: &u32, b: &u32| a.cmp(b));
+        let sorted = parallel_merge_sort(values, &|a: &u32, b: &u32| a.cmp(b), 4);
+
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_sort_by_key_desc_owned_and_refs() {
+        let rating = |sofifa_id, rating_sum, num_ratings| RatingPlayer {
+            sofifa_id,
+            rating_sum,
+            num_ratings,
+        };
+        let mut owned = vec![rating(1, 6.0, 2), rating(2, 0.0, 0), rating(3, 9.0, 2), rating(4, 3.0, 1)];
+
+        let mut refs: Vec<&RatingPlayer> = owned.iter().collect();
+        sort_players_by_rating(&mut refs);
+        let ids: Vec<u32> = refs.iter().map(|r| r.sofifa_id).collect();
+        assert_eq!(ids, vec![3, 1, 4, 2]);
+
+        sort_user_ratings(&mut owned);
+        let ids: Vec<u32> = owned.iter().map(|r| r.sofifa_id).collect();
+        assert_eq!(ids, vec![3, 1, 4, 2]);
+    }
+}
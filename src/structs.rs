@@ -1,7 +1,127 @@
 
-use serde::Deserialize;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize)]
+use crate::hash_table::HashMap;
+use crate::positions;
+
+// A row of the players file. Only the first seven columns are required. The
+// rest of a sofifa export is optional: a missing column or an empty cell
+// leaves the field as None, so minimal players files keep loading. Numeric
+// cells are kept as read until into_player checks them.
+#[derive(Debug, Deserialize)]
+pub struct PlayerRecord {
+    sofifa_id: u32,
+    short_name: String,
+    long_name: String,
+    player_positions: String,
+    nationality: String,
+    club_name: String,
+    league_name: String,
+    #[serde(default)]
+    age: Option<Cell>,
+    #[serde(default)]
+    height_cm: Option<Cell>,
+    #[serde(default)]
+    weight_kg: Option<Cell>,
+    #[serde(default)]
+    overall: Option<Cell>,
+    #[serde(default)]
+    potential: Option<Cell>,
+    #[serde(default)]
+    value_eur: Option<Cell>,
+    #[serde(default)]
+    wage_eur: Option<Cell>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    preferred_foot: Option<String>,
+    #[serde(default)]
+    weak_foot: Option<Cell>,
+    #[serde(default)]
+    skill_moves: Option<Cell>,
+    #[serde(default)]
+    pace: Option<Cell>,
+    #[serde(default)]
+    shooting: Option<Cell>,
+    #[serde(default)]
+    passing: Option<Cell>,
+    #[serde(default)]
+    dribbling: Option<Cell>,
+    #[serde(default)]
+    defending: Option<Cell>,
+    #[serde(default)]
+    physic: Option<Cell>,
+}
+
+// A numeric cell as read. Exports write whole numbers as 85, but also as
+// 85.0 or 1.05e+08. Anything else, such as true, is Other.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Cell {
+    Int(u64),
+    Float(f64),
+    Text(String),
+    Other(IgnoredAny),
+}
+
+impl Cell {
+    // The number it holds, floats rounded; None when it is not a number or
+    // does not fit
+    fn number(&self) -> Option<u32> {
+        let value = match self {
+            Cell::Int(n) => return u32::try_from(*n).ok(),
+            Cell::Float(f) => *f,
+            Cell::Text(text) => text.trim().parse::<f64>().ok()?,
+            Cell::Other(_) => return None,
+        };
+        let value = value.round();
+        (value >= 0.0 && value <= u32::MAX as f64).then_some(value as u32)
+    }
+}
+
+impl PlayerRecord {
+    // The player, and how many numeric cells were filled with something that
+    // is not a number. Those fields are left as None.
+    pub fn into_player(self) -> (Player, usize) {
+        let mut invalid = 0;
+        let mut number = |cell: Option<Cell>| match cell {
+            Some(Cell::Text(text)) if text.trim().is_empty() => None,
+            Some(cell) => {
+                let number = cell.number();
+                invalid += number.is_none() as usize;
+                number
+            }
+            None => None,
+        };
+        let player = Player {
+            sofifa_id: self.sofifa_id,
+            short_name: self.short_name,
+            long_name: self.long_name,
+            player_positions: self.player_positions,
+            nationality: self.nationality,
+            club_name: self.club_name,
+            league_name: self.league_name,
+            age: number(self.age),
+            height_cm: number(self.height_cm),
+            weight_kg: number(self.weight_kg),
+            overall: number(self.overall),
+            potential: number(self.potential),
+            value_eur: number(self.value_eur),
+            wage_eur: number(self.wage_eur),
+            preferred_foot: self.preferred_foot,
+            weak_foot: number(self.weak_foot),
+            skill_moves: number(self.skill_moves),
+            pace: number(self.pace),
+            shooting: number(self.shooting),
+            passing: number(self.passing),
+            dribbling: number(self.dribbling),
+            defending: number(self.defending),
+            physic: number(self.physic),
+        };
+        (player, invalid)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 #[allow(dead_code)]
 pub struct Player {
     pub sofifa_id: u32,
@@ -11,9 +131,47 @@ pub struct Player {
     pub nationality: String,
     pub club_name: String,
     pub league_name: String,
+    pub age: Option<u32>,
+    pub height_cm: Option<u32>,
+    pub weight_kg: Option<u32>,
+    pub overall: Option<u32>,
+    pub potential: Option<u32>,
+    pub value_eur: Option<u32>,
+    pub wage_eur: Option<u32>,
+    pub preferred_foot: Option<String>,
+    pub weak_foot: Option<u32>,
+    pub skill_moves: Option<u32>,
+    pub pace: Option<u32>,
+    pub shooting: Option<u32>,
+    pub passing: Option<u32>,
+    pub dribbling: Option<u32>,
+    pub defending: Option<u32>,
+    pub physic: Option<u32>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Player {
+    // "RW, st, CF" -> ["RW", "ST", "CF"], unknown codes are left out
+    pub fn positions(&self) -> Vec<&'static str> {
+        self.position_codes().filter_map(|s| positions::normalize(&s)).collect()
+    }
+
+    // The codes of player_positions that are not known positions
+    pub fn unknown_positions(&self) -> Vec<String> {
+        self.position_codes()
+            .filter(|s| positions::normalize(s).is_none())
+            .collect()
+    }
+
+    fn position_codes(&self) -> impl Iterator<Item = String> + '_ {
+        self.player_positions
+            .split(',')
+            .map(|s| s.trim()) // Remove leading and trailing whitespace
+            .map(|s| s.replace(' ', "")) // Remove spaces within the strings
+            .filter(|s| !s.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(dead_code)]
 pub struct RatingFile {
     pub user_id: u32,
@@ -21,6 +179,37 @@ pub struct RatingFile {
     pub rating: f32,
 }
 
+// A row type read from one of the input files. KIND names the section of the
+// column map that applies to it, REQUIRED lists the columns it cannot do
+// without.
+pub trait Record {
+    const KIND: &'static str;
+    const REQUIRED: &'static [&'static str];
+}
+
+impl Record for PlayerRecord {
+    const KIND: &'static str = "players";
+    const REQUIRED: &'static [&'static str] = &[
+        "sofifa_id",
+        "short_name",
+        "long_name",
+        "player_positions",
+        "nationality",
+        "club_name",
+        "league_name",
+    ];
+}
+
+impl Record for RatingFile {
+    const KIND: &'static str = "ratings";
+    const REQUIRED: &'static [&'static str] = &["user_id", "sofifa_id", "rating"];
+}
+
+impl Record for Tags {
+    const KIND: &'static str = "tags";
+    const REQUIRED: &'static [&'static str] = &["user_id", "sofifa_id", "tag"];
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[allow(dead_code)]
 pub struct RatingPlayer {
@@ -43,31 +232,195 @@ pub struct User {
     pub user_id: u32,
     pub ratings: Vec<RatingPlayer>,
 }
+impl RatingPlayer {
+    pub fn average(&self) -> f32 {
+        if self.num_ratings > 0 {
+            self.rating_sum / self.num_ratings as f32
+        } else {
+            0.0 // for players with no ratings
+        }
+    }
+}
+
+// Ratings go in half stars from 0.5 to 5.0, one bucket each
+pub const HISTOGRAM_BUCKETS: usize = 10;
+
+// How many users gave a player each rating
+#[derive(Debug, Clone)]
+pub struct RatingHistogram {
+    pub sofifa_id: u32,
+    pub buckets: [u32; HISTOGRAM_BUCKETS],
+}
+
+impl RatingHistogram {
+    pub fn new(sofifa_id: u32) -> Self {
+        RatingHistogram {
+            sofifa_id,
+            buckets: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    // Values between half stars go to the nearest one, values out of range
+    // to the first or last bucket
+    pub fn bucket(rating: f32) -> usize {
+        ((rating * 2.0).round() as i64 - 1).clamp(0, HISTOGRAM_BUCKETS as i64 - 1) as usize
+    }
+
+    // The rating a bucket stands for: 0.5 for the first, 5.0 for the last
+    pub fn bucket_rating(bucket: usize) -> f32 {
+        (bucket + 1) as f32 / 2.0
+    }
+
+    pub fn add(&mut self, rating: f32) {
+        self.buckets[Self::bucket(rating)] += 1;
+    }
+
+    pub fn remove(&mut self, rating: f32) {
+        let bucket = &mut self.buckets[Self::bucket(rating)];
+        *bucket = bucket.saturating_sub(1);
+    }
+
+    pub fn merge(&mut self, other: &RatingHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+}
+
+// Every tag a player got and how many times. Tags differing only in case
+// are counted together under the first spelling seen.
+#[derive(Debug, Clone)]
+pub struct PlayerTags {
+    pub sofifa_id: u32,
+    pub tags: Vec<(String, u32)>,
+}
+
+impl PlayerTags {
+    pub fn new(sofifa_id: u32) -> Self {
+        PlayerTags {
+            sofifa_id,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, tag: &str) {
+        let lower = tag.to_lowercase();
+        match self.tags.iter_mut().find(|(seen, _)| seen.to_lowercase() == lower) {
+            Some((_, count)) => *count += 1,
+            None => self.tags.push((tag.to_string(), 1)),
+        }
+    }
+
+    // Most used first, ties in alphabetical order
+    pub fn top(&self, n: usize) -> Vec<(&str, u32)> {
+        let mut tags: Vec<(&str, u32)> = self.tags.iter().map(|(tag, count)| (tag.as_str(), *count)).collect();
+        tags.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        tags.truncate(n);
+        tags
+    }
+}
+
+// What to do when a user rates the same player more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupPolicy {
+    KeepFirst,
+    KeepLast,
+    Average,
+    CountAll,
+}
+
+impl std::str::FromStr for DedupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "first" => Ok(DedupPolicy::KeepFirst),
+            "last" => Ok(DedupPolicy::KeepLast),
+            "average" => Ok(DedupPolicy::Average),
+            "all" => Ok(DedupPolicy::CountAll),
+            _ => Err(format!(
+                "Invalid dedup policy '{}', expected first, last, average or all",
+                s
+            )),
+        }
+    }
+}
+
+// Ratings and tags whose sofifa_id is not in players.csv. They are always
+// counted; with `keep` set they are also stored here instead of being dropped.
+#[derive(Debug)]
+pub struct Quarantine {
+    pub keep: bool,
+    pub orphan_ratings: usize,
+    pub orphan_tags: usize,
+    pub unknown_players: usize,
+    pub ratings: HashMap<u32, RatingPlayer>,
+    pub tags: Vec<Tags>,
+}
+
+impl Quarantine {
+    pub fn new(keep: bool) -> Self {
+        Quarantine {
+            keep,
+            orphan_ratings: 0,
+            orphan_tags: 0,
+            unknown_players: 0,
+            ratings: HashMap::new(100),
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn add_ratings(&mut self, orphan: RatingPlayer) {
+        self.orphan_ratings += orphan.num_ratings as usize;
+        if !self.keep {
+            return;
+        }
+
+        match self.ratings.search(&orphan.sofifa_id) {
+            Some(rating) => {
+                rating.rating_sum += orphan.rating_sum;
+                rating.num_ratings += orphan.num_ratings;
+            }
+            None => {
+                self.unknown_players += 1;
+                self.ratings.insert(orphan.sofifa_id, orphan);
+            }
+        }
+    }
+
+    pub fn add_tag(&mut self, tag: Tags) {
+        self.orphan_tags += 1;
+        if self.keep {
+            self.tags.push(tag);
+        }
+    }
+}
+
 pub trait Identifiable {
     fn id(&self) -> u32;
 }
 
 impl Identifiable for Player {
     fn id(&self) -> u32 {
-        self.sofifa_id as u32
+        self.sofifa_id
     }
 }
 
 impl Identifiable for User {
     fn id(&self) -> u32 {
-        self.user_id as u32
+        self.user_id
     }
 }
 
 impl Identifiable for RatingFile {
     fn id(&self) -> u32 {
-        self.sofifa_id as u32
+        self.sofifa_id
     }
 }
 
 impl Identifiable for RatingPlayer {
     fn id(&self) -> u32 {
-        self.sofifa_id as u32
+        self.sofifa_id
     }
 }
 pub trait AddRating<T> {
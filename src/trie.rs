@@ -1,3 +1,5 @@
+use crate::snapshot::{Decode, Decoder, Encode, Encoder};
+
 
 trait Identifiable {
     fn id(&self) -> u32;
@@ -5,15 +7,13 @@ trait Identifiable {
 
 impl Identifiable for i32 {
     fn id(&self) -> u32 {
-        let r = (*self) as u32;
-        r
+        (*self) as u32
     }
 }
 
 impl Identifiable for char {
     fn id(&self) -> u32 {
-        let r = (*self) as u32;
-        r
+        (*self) as u32
     }
 }
 #[derive(Default, Debug, Clone)]
@@ -53,6 +53,13 @@ impl<K:Eq + Identifiable, V> HashMap <K, V> {
         None
     }
 
+    fn get_ref(&self, key: &K) -> Option<&V> {
+        self.buckets[self.hash(key)]
+            .iter()
+            .find(|(existing_key, _)| existing_key == key)
+            .map(|(_, value)| value)
+    }
+
 }
 
 
@@ -73,6 +80,7 @@ impl TrieNode {
     }
 }
 
+#[derive(Clone)]
 pub struct Trie {
     root: TrieNode,
 }
@@ -133,13 +141,13 @@ impl Trie {
         node.is_end_of_word
     }
 
-    pub fn get_id(&mut self, word: &str) -> Option<Vec<u32>> {
-        let mut node = &mut self.root;
+    pub fn get_id(&self, word: &str) -> Option<Vec<u32>> {
+        let mut node = &self.root;
 
         let word_lower_case = word.to_lowercase();
 
         for ch in word_lower_case.chars() {
-            match node.children.get(&ch) {
+            match node.children.get_ref(&ch) {
                 Some(next_node) => node = next_node,
                 None => return None,
             }
@@ -165,35 +173,37 @@ impl Trie {
         true
     }
 
-    pub fn get_words_starting_with(&mut self, prefix: &str) -> Vec<String> {
-        let mut node = self.root.clone();
-            for ch in prefix.to_lowercase().chars() {
-                match node.children.get(&ch) {
-                    Some(next_node) => node = next_node.clone(),
-                    None => return Vec::new(),
-                }
+    pub fn get_words_starting_with(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.to_lowercase();
+
+        let mut node = &self.root;
+        for ch in prefix.chars() {
+            match node.children.get_ref(&ch) {
+                Some(next_node) => node = next_node,
+                None => return Vec::new(),
+            }
         }
-        
+
         let mut result = Vec::new();
-        
-        self.collect_words(&node, &prefix.to_string(), &mut result);
+
+        self.collect_words(node, &prefix, &mut result);
         result
     }
 
-    fn collect_words(&mut self, node: &TrieNode, prefix: &String, result: &mut Vec<String>) {
+    fn collect_words(&self, node: &TrieNode, prefix: &str, result: &mut Vec<String>) {
 
         if node.is_end_of_word {
-            result.push(prefix.clone());
+            result.push(prefix.to_string());
         }
 
+        // Characters that hash to the same bucket share it, so every entry
+        // of a bucket is a child
         for bucket in node.children.buckets.iter() {
-
-            if let Some((character, child_node)) = bucket.first() {
-                let mut new_prefix = prefix.clone();
+            for (character, child_node) in bucket {
+                let mut new_prefix = prefix.to_string();
                 new_prefix.push(*character);
                 self.collect_words(child_node, &new_prefix, result);
             }
-            
          }
     }
     
@@ -201,6 +211,54 @@ impl Trie {
 
 
 
+// Nodes are written depth-first with their children in bucket order, so a
+// decoded trie has exactly the same layout as the one that was saved
+impl Encode for Trie {
+    fn encode(&self, enc: &mut Encoder) {
+        encode_node(&self.root, enc);
+    }
+}
+
+fn encode_node(node: &TrieNode, enc: &mut Encoder) {
+    enc.u8(node.is_end_of_word as u8);
+    enc.len(node.has_id.len());
+    for id in &node.has_id {
+        enc.u32(*id);
+    }
+
+    let children: Vec<&(char, TrieNode)> = node.children.buckets.iter().flatten().collect();
+    enc.len(children.len());
+    for (ch, child) in children {
+        enc.u32(*ch as u32);
+        encode_node(child, enc);
+    }
+}
+
+impl Decode for Trie {
+    fn decode(dec: &mut Decoder) -> Result<Self, String> {
+        Ok(Trie {
+            root: decode_node(dec)?,
+        })
+    }
+}
+
+fn decode_node(dec: &mut Decoder) -> Result<TrieNode, String> {
+    let mut node = TrieNode::new();
+    node.is_end_of_word = dec.u8()? != 0;
+
+    let ids = dec.len()?;
+    for _ in 0..ids {
+        node.has_id.push(dec.u32()?);
+    }
+
+    let children = dec.len()?;
+    for _ in 0..children {
+        let ch = char::from_u32(dec.u32()?).ok_or("invalid character in trie")?;
+        node.children.insert(ch, decode_node(dec)?);
+    }
+    Ok(node)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -289,4 +347,21 @@ mod tests {
         let words_with_nonexistent_prefix = trie.get_words_starting_with("nonexistent");
         assert_eq!(words_with_nonexistent_prefix, Vec::<String>::new());
     }
+
+    #[test]
+    fn test_get_words_starting_with_colliding_children() {
+        let mut trie = Trie::new();
+
+        // 'a' (97) and '-' (45) land in the same bucket of a node's children,
+        // collect_words used to follow only the first entry of each bucket
+        trie.insert_with_id("x-ray", 1);
+        trie.insert_with_id("xa", 2);
+        trie.insert_with_id("xab", 3);
+
+        let mut words_with_x = trie.get_words_starting_with("X");
+        words_with_x.sort();
+        assert_eq!(words_with_x, vec!["x-ray", "xa", "xab"]);
+        assert_eq!(trie.get_words_starting_with("xa"), vec!["xa", "xab"]);
+        assert_eq!(trie.get_id("x-ray"), Some(vec![1]));
+    }
 }